@@ -1,6 +1,14 @@
 #![allow(warnings)]
+use crate::rpchandler::batch;
+use crate::rpchandler::eventTypes::{self, DecodedLog};
+use crate::rpchandler::expr::{self, Expr, ExprValue};
+use crate::rpchandler::history::{self, DeliveryFilter, DeliveryRecord};
 use crate::rpchandler::relayer::{RelayerCommand, RelayerHandler};
 use crate::rpchandler::rpc_types::{RpcTypes, SubscriptionType};
+use crate::rpchandler::scheduler::{ActionSpec, Schedule, ScheduleSpec};
+use crate::rpchandler::sinks::{SinkSpec, VerificationStatus};
+use crate::rpchandler::template::PayloadTemplate;
+use crate::rpchandler::transactionTypes::{self, ActionOrder, ActionPlan, RawTransaction};
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
@@ -8,7 +16,8 @@ use std::str::FromStr;
 use std::sync::Arc;
 
 use alloy::dyn_abi::ErrorExt;
-use alloy::primitives::{Address, Signature};
+use alloy::json_abi::{Event, JsonAbi};
+use alloy::primitives::{Address, B256, Signature};
 
 use alloy::rpc::types::Log;
 use dashmap::DashMap;
@@ -41,6 +50,9 @@ pub struct RelayerService {
 //     rpc GetLogs(GetUserLogsRequest) returns (UserLogs);
 //     rpc Subscribe(SubscriptionRequest) returns (SubscriptionResponse);
 //     rpc UnSubscribe(UnsubscribeRequest) returns (google.protobuf.Empty);
+//     rpc GetSubscription(GetSubscriptionRequest) returns (SubscriptionInfo);
+//     rpc ListDeliveries(ListDeliveriesRequest) returns (DeliveryList);
+//     rpc GetDelivery(GetDeliveryRequest) returns (Delivery);
 
 #[tonic::async_trait]
 impl ChainHooks for RelayerService {
@@ -170,10 +182,11 @@ impl ChainHooks for RelayerService {
         self.RelayerCommand_sender.send((req, tx));
         let res = rx.await.unwrap();
         match res {
-            RpcTypes::Logs { logs } => {
+            RpcTypes::Logs { logs, events } => {
                 return Ok(Response::new(UserLogs {
                     address: user,
                     logs: logs.into_iter().map(|log| format!("{:?}", log)).collect(),
+                    events: events.into_iter().map(event_log).collect(),
                 }));
             }
             _ => {}
@@ -185,6 +198,7 @@ impl ChainHooks for RelayerService {
         userRequest: Request<SubscriptionRequest>,
     ) -> Result<Response<SubscriptionResponse>, Status> {
         let req = userRequest.into_inner();
+        let order = req.order();
         let user = Address::from_str(&req.address).unwrap();
         let usertx = UserTx::new(user.to_string(), req.signature).unwrap();
         if let Some(n) = self.user_nonce.get(&user) {
@@ -198,25 +212,126 @@ impl ChainHooks for RelayerService {
         }
         let sub = req.details.unwrap();
         let cid = sub.chain_id as usize;
-        let rpc_command = SubscriptionType::Subscription {
-            user,
-            chainid: cid,
-            address: sub.target_address.parse::<Address>().unwrap(),
-            event_signature: sub.event_signature,
+        let mut event = None;
+        let mut event_signature = String::new();
+        let trigger = sub.trigger();
+        let actions: Vec<&RelayerAction> = req.action.iter().chain(&req.actions).collect();
+        if trigger == TriggerKind::Logs {
+            (event_signature, event) = log_event(&sub)?;
+        } else if sub.from_block.is_some() {
+            return Err(Status::invalid_argument(
+                "from_block is only supported for log subscriptions",
+            ));
+        }
+        let rpc_command = match sub.trigger() {
+            TriggerKind::NewHeads => SubscriptionType::NewHeads {
+                user,
+                chainid: cid,
+                every: sub.every_n_blocks,
+            },
+            TriggerKind::PendingTx => {
+                let (cmd, function) = pending_tx_subscription(user, cid, sub)?;
+                event = function;
+                cmd
+            }
+            TriggerKind::NativeTransfers => {
+                let addresses = target_addresses(&sub)?;
+                if addresses.is_empty() {
+                    return Err(Status::invalid_argument("No target address"));
+                }
+                // only templates read it, action params are resolved at runtime
+                event = Some(eventTypes::transfer_event());
+                SubscriptionType::Transfers {
+                    user,
+                    chainid: cid,
+                    addresses,
+                    internal: sub.include_internal,
+                }
+            }
+            TriggerKind::Logs => {
+                let needs_block = actions.iter().any(|action| {
+                    let calls = action.params.iter().filter_map(|p| p.call.as_ref());
+                    let mut calls = calls.chain(&action.guard_calls);
+                    action
+                        .params
+                        .iter()
+                        .any(|p| transactionTypes::uses_block(&p.params))
+                        || calls.any(|c| c.args.iter().any(|a| transactionTypes::uses_block(a)))
+                        || action.guards.iter().any(|g| g.contains("block."))
+                });
+                log_subscription(
+                    user,
+                    cid,
+                    sub,
+                    event_signature.clone(),
+                    event.clone(),
+                    needs_block,
+                )?
+            }
+        };
+        for action in &actions {
+            let chainid = action.chain_id as usize;
+            if self.RpcHandler.lock().await.channel(chainid).is_none() {
+                return Err(Status::invalid_argument(format!(
+                    "Chain {chainid} is not connected"
+                )));
+            }
+            // pending transactions are typed by their selected function, other triggers build
+            // their event fields at runtime
+            let mut types = None;
+            if matches!(trigger, TriggerKind::Logs | TriggerKind::PendingTx) {
+                let call_args = action.params.iter().filter_map(|p| p.call.as_ref());
+                let call_args = call_args.chain(&action.guard_calls);
+                let call_args = call_args.flat_map(|c| c.args.iter().chain([&c.target]));
+                for param in action.params.iter().map(|p| &p.params).chain(call_args) {
+                    if let Err(e) = transactionTypes::check_param(param, event.as_ref()) {
+                        return Err(Status::invalid_argument(e.to_string()));
+                    }
+                }
+                types = Some(expr::param_types(event.as_ref()));
+            }
+            if let Err(e) = check_action(action, types.as_ref()) {
+                return Err(Status::invalid_argument(e));
+            }
+        }
+        let steps = match actions.into_iter().map(raw_transaction).collect() {
+            Ok(steps) => steps,
+            Err(e) => return Err(Status::invalid_argument(e)),
+        };
+        let plan = ActionPlan {
+            steps,
+            order: match order {
+                ActionOrderKind::Parallel => ActionOrder::Parallel,
+                ActionOrderKind::Sequential => ActionOrder::Sequential {
+                    stop_on_failure: req.stop_on_failure,
+                },
+            },
+        };
+        let mut template = None;
+        if !req.payload_template.is_empty() {
+            match PayloadTemplate::parse(&req.payload_template, event.as_ref()) {
+                Ok(t) => template = Some(t),
+                Err(e) => return Err(Status::invalid_argument(e.to_string())),
+            }
+        }
+        let mut sinks = Vec::new();
+        for config in req.sinks {
+            match sink_spec(config).build() {
+                Ok(sink) => sinks.push(sink),
+                Err(e) => return Err(Status::invalid_argument(e.to_string())),
+            }
+        }
+        let delivery = match delivery_mode(req.delivery).validate() {
+            Ok(mode) => mode,
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
         };
 
         let (tx, rx) = oneshot::channel::<RpcTypes>();
 
-        let mut ch = self
-            .RpcHandler
-            .lock()
-            .await
-            .chain_state
-            .get_mut(&cid)
-            .unwrap()
-            .channel
-            .clone()
-            .unwrap();
+        let ch = match self.RpcHandler.lock().await.channel(cid) {
+            Some(ch) => ch,
+            None => return Err(Status::unavailable(format!("Chain {cid} is not connected"))),
+        };
 
         ch.send((rpc_command, tx)).await;
 
@@ -224,22 +339,20 @@ impl ChainHooks for RelayerService {
 
         match res {
             RpcTypes::Response { success, message } => {
+                if !success {
+                    return Err(Status::failed_precondition(message));
+                }
                 if success {
-                    let action = req.action.unwrap();
-                    let params = action
-                        .params
-                        .into_iter()
-                        .map(|p| (p.pos as usize, p.params))
-                        .collect::<Vec<_>>();
+                    let sub_id = message.clone();
 
                     let relayer_command = RelayerCommand::DefineRelayerAction {
                         user: user.to_string(),
                         sub_id: message,
-                        chainid: action.chain_id as usize,
-                        target_address: action.target_address,
-                        ABI: action.abi,
-                        function_name: action.function_name,
-                        Params: params,
+                        chainid: cid,
+                        plan,
+                        template,
+                        sinks,
+                        delivery,
                     };
                     let (tx, rx) = oneshot::channel::<RpcTypes>();
                     self.RelayerCommand_sender.send((relayer_command, tx)).await;
@@ -249,7 +362,7 @@ impl ChainHooks for RelayerService {
                         RpcTypes::Response { success, message } => {
                             if success {
                                 return Ok(Response::new(SubscriptionResponse {
-                                    subscription_id: message,
+                                    subscription_id: sub_id,
                                     success: true,
                                     message: String::from("Subscription Created"),
                                 }));
@@ -297,6 +410,615 @@ impl ChainHooks for RelayerService {
             }
         }
     }
+    async fn create_schedule(
+        &self,
+        userRequest: Request<CreateScheduleRequest>,
+    ) -> Result<Response<ScheduleInfo>, Status> {
+        let req = userRequest.into_inner();
+        let user = Address::from_str(&req.address).unwrap();
+        let usertx = UserTx::new(user.to_string(), req.signature).unwrap();
+        if let Some(n) = self.user_nonce.get(&user) {
+            if !usertx
+                .VerifyUser(*n)
+                .await
+                .expect("User verification failed")
+            {
+                return Err(Status::permission_denied("Not Authenticated"));
+            }
+        }
+        let spec = if req.cron.is_empty() {
+            ScheduleSpec::Interval {
+                secs: req.interval_seconds,
+            }
+        } else {
+            ScheduleSpec::Cron { expr: req.cron }
+        };
+        if let Err(e) = spec.validate() {
+            return Err(Status::invalid_argument(e.to_string()));
+        }
+        if let Some(action) = &req.action {
+            if let Err(e) = check_action(action, None) {
+                return Err(Status::invalid_argument(e));
+            }
+        }
+        let action = match req.action {
+            Some(action) => {
+                let (params, calls) = action_params(&action);
+                let gas = match gas_policy(action.gas.as_ref()) {
+                    Ok(gas) => gas,
+                    Err(e) => return Err(Status::invalid_argument(e)),
+                };
+                ActionSpec {
+                    chainid: action.chain_id as usize,
+                    target_address: action.target_address,
+                    abi: action.abi,
+                    function_name: action.function_name,
+                    params,
+                    calls,
+                    guards: action.guards,
+                    gas,
+                }
+            }
+            None => return Err(Status::invalid_argument("No relayer action")),
+        };
+        let mut template = None;
+        if !req.payload_template.is_empty() {
+            match PayloadTemplate::parse(&req.payload_template, Some(&eventTypes::schedule_event()))
+            {
+                Ok(t) => template = Some(t),
+                Err(e) => return Err(Status::invalid_argument(e.to_string())),
+            }
+        }
+        let mut sinks = Vec::new();
+        for config in req.sinks {
+            match sink_spec(config).build() {
+                Ok(sink) => sinks.push(sink),
+                Err(e) => return Err(Status::invalid_argument(e.to_string())),
+            }
+        }
+        let delivery = match delivery_mode(req.delivery).validate() {
+            Ok(mode) => mode,
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        };
+        let relayer_command = RelayerCommand::CreateSchedule {
+            user: user.to_string(),
+            spec,
+            action,
+            template,
+            sinks,
+            delivery,
+        };
+
+        let (tx, rx) = oneshot::channel::<RpcTypes>();
+        self.RelayerCommand_sender.send((relayer_command, tx)).await;
+        let res = rx.await.expect("Failed to receive response");
+        match res {
+            RpcTypes::Schedule { schedule } => Ok(Response::new(schedule_info(schedule))),
+            RpcTypes::Response { message, .. } => Err(Status::invalid_argument(message)),
+            _ => Err(Status::internal("error while creating the schedule")),
+        }
+    }
+
+    async fn pause_schedule(
+        &self,
+        userRequest: Request<PauseScheduleRequest>,
+    ) -> Result<Response<ScheduleInfo>, Status> {
+        let req = userRequest.into_inner();
+        let user = Address::from_str(&req.address).unwrap();
+        let usertx = UserTx::new(user.to_string(), req.signature).unwrap();
+        if let Some(n) = self.user_nonce.get(&user) {
+            if !usertx
+                .VerifyUser(*n)
+                .await
+                .expect("User verification failed")
+            {
+                return Err(Status::permission_denied("Not Authenticated"));
+            }
+        }
+        let relayer_command = RelayerCommand::PauseSchedule {
+            user: user.to_string(),
+            id: req.schedule_id,
+            paused: req.paused,
+        };
+
+        let (tx, rx) = oneshot::channel::<RpcTypes>();
+        self.RelayerCommand_sender.send((relayer_command, tx)).await;
+        let res = rx.await.expect("Failed to receive response");
+        match res {
+            RpcTypes::Schedule { schedule } => Ok(Response::new(schedule_info(schedule))),
+            _ => Err(Status::not_found("Schedule not found")),
+        }
+    }
+
+    async fn get_subscription(
+        &self,
+        userRequest: Request<GetSubscriptionRequest>,
+    ) -> Result<Response<SubscriptionInfo>, Status> {
+        let req = userRequest.into_inner();
+        let user = Address::from_str(&req.address).unwrap();
+        let usertx = UserTx::new(user.to_string(), req.signature).unwrap();
+        if let Some(n) = self.user_nonce.get(&user) {
+            if !usertx
+                .VerifyUser(*n)
+                .await
+                .expect("User verification failed")
+            {
+                return Err(Status::permission_denied("Not Authenticated"));
+            }
+        }
+        let relayer_command = RelayerCommand::GetSubscription {
+            user: user.to_string(),
+            sub_id: req.subscription_id,
+        };
+
+        let (tx, rx) = oneshot::channel::<RpcTypes>();
+        self.RelayerCommand_sender.send((relayer_command, tx)).await;
+        let res = rx.await.expect("Failed to receive response");
+        match res {
+            RpcTypes::SubscriptionInfo { sub_id, sinks } => {
+                let sinks = sinks
+                    .into_iter()
+                    .map(|(destination, status)| {
+                        let (verification, error) = match status {
+                            VerificationStatus::Pending => {
+                                (VerificationState::Pending, String::new())
+                            }
+                            VerificationStatus::Verified => {
+                                (VerificationState::Verified, String::new())
+                            }
+                            VerificationStatus::Failed(e) => (VerificationState::Failed, e),
+                        };
+                        SinkStatus {
+                            destination,
+                            verification: verification as i32,
+                            error,
+                        }
+                    })
+                    .collect();
+                Ok(Response::new(SubscriptionInfo {
+                    subscription_id: sub_id,
+                    sinks,
+                }))
+            }
+            _ => Err(Status::not_found("Subscription not found")),
+        }
+    }
+
+    async fn list_deliveries(
+        &self,
+        userRequest: Request<ListDeliveriesRequest>,
+    ) -> Result<Response<DeliveryList>, Status> {
+        let req = userRequest.into_inner();
+        let user = Address::from_str(&req.address).unwrap();
+        let usertx = UserTx::new(user.to_string(), req.signature.clone()).unwrap();
+        if let Some(n) = self.user_nonce.get(&user) {
+            if !usertx
+                .VerifyUser(*n)
+                .await
+                .expect("User verification failed")
+            {
+                return Err(Status::permission_denied("Not Authenticated"));
+            }
+        }
+        let state = match req.state() {
+            DeliveryState::Any => None,
+            DeliveryState::Pending => Some(history::DeliveryState::Pending),
+            DeliveryState::Succeeded => Some(history::DeliveryState::Succeeded),
+            DeliveryState::Failed => Some(history::DeliveryState::Failed),
+            DeliveryState::Skipped => Some(history::DeliveryState::Skipped),
+        };
+        let filter = DeliveryFilter {
+            sub_id: Some(req.subscription_id).filter(|s| !s.is_empty()),
+            state,
+            from: Some(req.from_ms).filter(|t| *t > 0),
+            to: Some(req.to_ms).filter(|t| *t > 0),
+            limit: req.limit as usize,
+        };
+        let relayer_command = RelayerCommand::ListDeliveries {
+            user: user.to_string(),
+            filter,
+        };
+
+        let (tx, rx) = oneshot::channel::<RpcTypes>();
+        self.RelayerCommand_sender.send((relayer_command, tx)).await;
+        let res = rx.await.expect("Failed to receive response");
+        match res {
+            RpcTypes::Deliveries { records } => Ok(Response::new(DeliveryList {
+                deliveries: records.into_iter().map(delivery).collect(),
+            })),
+            _ => Err(Status::internal("error while listing deliveries")),
+        }
+    }
+
+    async fn get_delivery(
+        &self,
+        userRequest: Request<GetDeliveryRequest>,
+    ) -> Result<Response<Delivery>, Status> {
+        let req = userRequest.into_inner();
+        let user = Address::from_str(&req.address).unwrap();
+        let usertx = UserTx::new(user.to_string(), req.signature).unwrap();
+        if let Some(n) = self.user_nonce.get(&user) {
+            if !usertx
+                .VerifyUser(*n)
+                .await
+                .expect("User verification failed")
+            {
+                return Err(Status::permission_denied("Not Authenticated"));
+            }
+        }
+        let relayer_command = RelayerCommand::GetDelivery {
+            user: user.to_string(),
+            id: req.delivery_id,
+        };
+
+        let (tx, rx) = oneshot::channel::<RpcTypes>();
+        self.RelayerCommand_sender.send((relayer_command, tx)).await;
+        let res = rx.await.expect("Failed to receive response");
+        match res {
+            RpcTypes::Deliveries { records } => match records.into_iter().next() {
+                Some(record) => Ok(Response::new(delivery(record))),
+                None => Err(Status::not_found("Delivery not found")),
+            },
+            _ => Err(Status::not_found("Delivery not found")),
+        }
+    }
+}
+
+/// `target_address` and `target_addresses` merged, without duplicates
+fn target_addresses(sub: &SubscriptionDetails) -> Result<Vec<Address>, Status> {
+    let mut addresses = Vec::new();
+    for addr in std::iter::once(&sub.target_address)
+        .chain(sub.target_addresses.iter())
+        .filter(|a| !a.is_empty())
+    {
+        match addr.parse::<Address>() {
+            Ok(a) if !addresses.contains(&a) => addresses.push(a),
+            Ok(_) => {}
+            Err(_) => return Err(Status::invalid_argument(format!("Invalid address {addr}"))),
+        }
+    }
+    Ok(addresses)
+}
+
+/// Parses the event of a log subscription into its canonical signature and the event to decode with
+fn log_event(sub: &SubscriptionDetails) -> Result<(String, Option<Event>), Status> {
+    eventTypes::log_event(&sub.event_signature, &sub.event_abi)
+        .map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+/// Checks a relayer action against its function ABI, actions without an ABI aren't checked
+fn check_action(
+    action: &RelayerAction,
+    types: Option<&HashMap<String, expr::ExprType>>,
+) -> Result<(), String> {
+    let raw_tran = raw_transaction(action)?;
+    if action.abi.is_empty() {
+        return Ok(());
+    }
+    raw_tran.check(types).map_err(|e| e.to_string())
+}
+
+fn raw_transaction(action: &RelayerAction) -> Result<RawTransaction, String> {
+    let (params, calls) = action_params(action);
+    let mut raw_tran = RawTransaction::new(
+        action.chain_id as usize,
+        action.target_address.clone(),
+        action.abi.clone(),
+        action.function_name.clone(),
+        params,
+    );
+    raw_tran.skip_historical = action.skip_historical;
+    raw_tran.calls = calls;
+    raw_tran.guards = action.guards.clone();
+    raw_tran.gas = gas_policy(action.gas.as_ref())?;
+    Ok(raw_tran)
+}
+
+/// Reads an action's gas policy, amounts go through the expression parser so units like gwei work
+fn gas_policy(gas: Option<&GasPolicy>) -> Result<transactionTypes::GasPolicy, String> {
+    let gas = match gas {
+        Some(gas) => gas,
+        None => return Ok(Default::default()),
+    };
+    let amount = |field: &str, value: &str| -> Result<Option<u128>, String> {
+        if value.trim().is_empty() {
+            return Ok(None);
+        }
+        match Expr::parse(value).and_then(|e| e.eval(&HashMap::new())) {
+            Ok(ExprValue::Uint(v, _)) => match u128::try_from(v) {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(format!("{field} is too large")),
+            },
+            _ => Err(format!("Invalid {field} {value}")),
+        }
+    };
+    let multiplier = gas.gas_limit_multiplier;
+    if !multiplier.is_finite() || multiplier < 0.0 {
+        return Err(format!("Invalid gas_limit_multiplier {multiplier}"));
+    }
+    let policy = transactionTypes::GasPolicy {
+        max_fee_per_gas: amount("max_fee_per_gas", &gas.max_fee_per_gas)?,
+        max_priority_fee_per_gas: amount(
+            "max_priority_fee_per_gas",
+            &gas.max_priority_fee_per_gas,
+        )?,
+        gas_limit: (gas.gas_limit > 0).then_some(gas.gas_limit),
+        gas_limit_multiplier: (multiplier > 0.0).then_some(multiplier),
+        max_base_fee: amount("max_base_fee", &gas.max_base_fee)?,
+    };
+    if let (Some(max_fee), Some(priority_fee)) =
+        (policy.max_fee_per_gas, policy.max_priority_fee_per_gas)
+    {
+        if priority_fee > max_fee {
+            return Err("max_priority_fee_per_gas is above max_fee_per_gas".to_string());
+        }
+    }
+    Ok(policy)
+}
+
+/// Action parameters and their view calls, a parameter read from a view call becomes `call:<n>`.
+/// Guard-only view calls come after those of the parameters
+fn action_params(
+    action: &RelayerAction,
+) -> (Vec<(usize, String)>, Vec<transactionTypes::ViewCall>) {
+    let mut calls = Vec::new();
+    let params = action
+        .params
+        .iter()
+        .map(|p| {
+            let value = match &p.call {
+                Some(call) => {
+                    calls.push(view_call(call));
+                    format!("call:{}", calls.len() - 1)
+                }
+                None => p.params.clone(),
+            };
+            (p.pos as usize, value)
+        })
+        .collect();
+    calls.extend(action.guard_calls.iter().map(view_call));
+    (params, calls)
+}
+
+fn view_call(call: &ViewCall) -> transactionTypes::ViewCall {
+    transactionTypes::ViewCall {
+        target: call.target.clone(),
+        function: call.function.clone(),
+        args: call.args.clone(),
+        output: call.output as usize,
+    }
+}
+
+/// Validates the contract log part of a subscription request
+fn log_subscription(
+    user: Address,
+    chainid: usize,
+    sub: SubscriptionDetails,
+    event_signature: String,
+    event: Option<Event>,
+    needs_block: bool,
+) -> Result<SubscriptionType, Status> {
+    let addresses = target_addresses(&sub)?;
+    if sub.any_address && !addresses.is_empty() {
+        return Err(Status::invalid_argument(
+            "any_address can't be combined with target addresses",
+        ));
+    }
+    if !sub.any_address && addresses.is_empty() {
+        return Err(Status::invalid_argument("No target address"));
+    }
+    if sub.any_address && event_signature.is_empty() {
+        return Err(Status::invalid_argument(
+            "any_address subscriptions need an event signature",
+        ));
+    }
+    let mut topics: [Vec<B256>; 3] = Default::default();
+    for (i, filter) in [&sub.topic1, &sub.topic2, &sub.topic3]
+        .into_iter()
+        .enumerate()
+    {
+        let values = match filter {
+            Some(filter) => &filter.values,
+            None => continue,
+        };
+        match eventTypes::topic_filter(event.as_ref(), i + 1, values) {
+            Ok(t) => topics[i] = t,
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        }
+    }
+    let mut filter = None;
+    if !sub.filter.is_empty() {
+        match Expr::predicate(&sub.filter, &expr::event_types(event.as_ref())) {
+            Ok(f) => filter = Some(f),
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        }
+    }
+    Ok(SubscriptionType::Subscription {
+        user,
+        chainid,
+        addresses,
+        event_signature,
+        event,
+        topics,
+        filter,
+        from_block: sub.from_block,
+        needs_block,
+    })
+}
+
+/// Validates a pending transaction subscription, `target_addresses` are the accepted `to` addresses.
+/// When it selects a single function, its inputs are returned as an event that types `event.*` fields
+fn pending_tx_subscription(
+    user: Address,
+    chainid: usize,
+    sub: SubscriptionDetails,
+) -> Result<(SubscriptionType, Option<Event>), Status> {
+    let to = target_addresses(&sub)?;
+    let mut abi = JsonAbi::new();
+    if !sub.event_abi.is_empty() {
+        match eventTypes::parse_function_abi(&sub.event_abi) {
+            Ok(a) => abi = a,
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        }
+    }
+    let mut selectors = Vec::new();
+    for selector in &sub.selectors {
+        let (selector, function) = match eventTypes::parse_selector(selector) {
+            Ok(parsed) => parsed,
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        };
+        selectors.push(selector);
+        match function {
+            Some(function) if !abi.functions().any(|f| f.selector() == selector) => {
+                abi.functions
+                    .entry(function.name.clone())
+                    .or_default()
+                    .push(function);
+            }
+            _ => {}
+        }
+    }
+    // an ABI with a single function selects it
+    if selectors.is_empty() {
+        if let [function] = abi.functions().collect::<Vec<_>>().as_slice() {
+            selectors.push(function.selector());
+        }
+    }
+    if to.is_empty() && selectors.is_empty() {
+        return Err(Status::invalid_argument(
+            "pending transaction subscriptions need a target address or a selector",
+        ));
+    }
+    let function = match selectors.as_slice() {
+        [selector] => abi.functions().find(|f| f.selector() == *selector),
+        _ => None,
+    };
+    let event = function.map(eventTypes::calldata_event);
+    let abi = match abi.functions().next() {
+        Some(_) => Some(abi),
+        None => None,
+    };
+    let cmd = SubscriptionType::PendingTx {
+        user,
+        chainid,
+        to,
+        selectors,
+        abi,
+    };
+    Ok((cmd, event))
+}
+
+fn event_log(log: DecodedLog) -> EventLog {
+    let args = log
+        .args
+        .iter()
+        .map(|arg| {
+            (
+                arg.name.clone(),
+                EventArg {
+                    sol_type: arg.sol_type.clone(),
+                    value: eventTypes::sol_value_to_string(&arg.value),
+                    indexed: arg.indexed,
+                    position: arg.position as u32,
+                },
+            )
+        })
+        .collect();
+    EventLog {
+        subscription_id: log.sub_id,
+        event_name: log.event_name,
+        args,
+        address: log.address.to_string(),
+        block_number: log.block_number.unwrap_or_default(),
+        block_hash: log.block_hash.map(|h| h.to_string()).unwrap_or_default(),
+        tx_hash: log.tx_hash.map(|h| h.to_string()).unwrap_or_default(),
+        log_index: log.log_index.unwrap_or_default(),
+        removed: log.removed,
+        historical: log.historical,
+        decode_error: log.decode_error.unwrap_or_default(),
+        payload: log.payload.map(|p| p.to_string()).unwrap_or_default(),
+    }
+}
+
+fn sink_spec(config: SinkConfig) -> SinkSpec {
+    match config.kind() {
+        SinkKind::Webhook => SinkSpec::Webhook {
+            url: config.url,
+            headers: config.headers.into_iter().collect(),
+        },
+        SinkKind::Nats => SinkSpec::Nats {
+            url: config.url,
+            subject: config.topic,
+        },
+        SinkKind::RedisStream => SinkSpec::RedisStream {
+            url: config.url,
+            stream: config.topic,
+        },
+    }
+}
+
+fn delivery_mode(mode: Option<DeliveryMode>) -> batch::DeliveryMode {
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return batch::DeliveryMode::Immediate,
+    };
+    let window = Duration::from_millis(mode.window_ms);
+    match mode.kind() {
+        DeliveryModeKind::Immediate => batch::DeliveryMode::Immediate,
+        DeliveryModeKind::Batch => batch::DeliveryMode::Batch {
+            max_events: mode.max_events as usize,
+            max_bytes: mode.max_bytes as usize,
+            window,
+        },
+        DeliveryModeKind::BlockDigest => batch::DeliveryMode::BlockDigest { window },
+    }
+}
+
+fn delivery(record: DeliveryRecord) -> Delivery {
+    let kind = match record.kind {
+        history::DeliveryKind::Sink => DeliveryKind::Sink,
+        history::DeliveryKind::Action => DeliveryKind::Action,
+        history::DeliveryKind::Backfill => DeliveryKind::Backfill,
+    };
+    let state = match record.state {
+        history::DeliveryState::Pending => DeliveryState::Pending,
+        history::DeliveryState::Succeeded => DeliveryState::Succeeded,
+        history::DeliveryState::Failed => DeliveryState::Failed,
+        history::DeliveryState::Skipped => DeliveryState::Skipped,
+    };
+    Delivery {
+        id: record.id,
+        subscription_id: record.sub_id,
+        event_id: record.event_id,
+        kind: kind as i32,
+        destination: record.destination,
+        attempts: record.attempts,
+        state: state as i32,
+        http_status: record.http_status.unwrap_or_default() as u32,
+        tx_hash: record.tx_hash.unwrap_or_default(),
+        error: record.error,
+        latency_ms: record.latency_ms,
+        created_at_ms: record.created_at,
+        updated_at_ms: record.updated_at,
+        step: record.step.unwrap_or_default() as u32,
+    }
+}
+
+fn schedule_info(schedule: Schedule) -> ScheduleInfo {
+    let (cron, interval_seconds) = match schedule.spec {
+        ScheduleSpec::Cron { expr } => (expr, 0),
+        ScheduleSpec::Interval { secs } => (String::new(), secs),
+    };
+    ScheduleInfo {
+        schedule_id: schedule.id,
+        cron,
+        interval_seconds,
+        paused: schedule.paused,
+        next_run_at_ms: schedule.next_run,
+        last_run_at_ms: schedule.last_run.unwrap_or_default(),
+        runs: schedule.runs,
+        paused_reason: schedule.paused_reason,
+    }
 }
 
 pub struct UserTx {
@@ -327,40 +1049,55 @@ impl UserTx {
     }
 }
 
-// pub fn Intialize() -> RelayerService {
-//     dotenv().ok();
-
-//     let chains = [
-//         (1, "ETHEREUM"),
-//         (137, "POLYGON"),
-//         (42161, "ARBITRUM"),
-//         (10, "OPTIMISM"),
-//         (11155111, "SEPOLIA"),
-//     ];
-
-//     let available_chains: HashMap<_, _> = chains.into_iter().collect();
-
-//     let available_chains = vec![1, 137.10, 42161, 11155111];
-
-//     let mut rpc_handler = RPChandler::new(available_chains);
-
-//     for (chain, name) in chains {
-//         if let Some(url) = env::var(name) {
-//             rpc_handler.new_chainstate(chain, url);
-//         }
-//     }
+/// Chains served when their websocket URL is set in the environment
+const CHAINS: [(usize, &str); 5] = [
+    (1, "ETHEREUM"),
+    (137, "POLYGON"),
+    (42161, "ARBITRUM"),
+    (10, "OPTIMISM"),
+    (11155111, "SEPOLIA"),
+];
+const DEFAULT_GRPC_ADDR: &str = "[::1]:50051";
 
-//     let (log_tx, log_rx) = mpsc::channel::<Log>(100);
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    dotenv().ok();
 
-//     let (relayer_tx, relayer_rx) =
-//         mpsc::channel::<(RelayerCommand, oneshot::Sender<RpcTypes>)>(100);
+    let mut rpc_handler = RPChandler::new(CHAINS.iter().map(|(chain, _)| *chain).collect());
+    for (chain, name) in CHAINS {
+        if let Ok(url) = env::var(name) {
+            rpc_handler.new_chainstate(chain, url);
+        }
+    }
 
-//     let relayer_service = RelayerHandler::new_handler(log_rx, relayer_rx);
+    let (log_tx, log_rx) = mpsc::channel::<RpcTypes>(100);
+    let (relayer_tx, relayer_rx) =
+        mpsc::channel::<(RelayerCommand, oneshot::Sender<RpcTypes>)>(100);
 
-//     let Subscriptions = SubscriptionType::Subscription { user: , chainid: (), address: (), event_signature: () }
-// }
+    // every chain worker reports to the one relayer, which routes actions back by chain id
+    let mut relayer = RelayerHandler::new_handler();
+    for (chain, name) in CHAINS {
+        if !rpc_handler.chain_state.contains_key(&chain) {
+            continue;
+        }
+        match rpc_handler.connect(chain, log_tx.clone()).await {
+            Ok(sender) => relayer.add_chain(chain, sender),
+            Err(e) => eprintln!("{name} ({chain}) unavailable: {e}"),
+        }
+    }
+    relayer.run(log_rx, relayer_rx).await?;
 
-#[tokio::main]
-async fn main() {
-    // let handler = Intialize();
+    let service = RelayerService {
+        user_nonce: Default::default(),
+        RelayerCommand_sender: relayer_tx,
+        RpcHandler: Mutex::new(rpc_handler),
+    };
+    let addr = env::var("GRPC_ADDR")
+        .unwrap_or(DEFAULT_GRPC_ADDR.to_string())
+        .parse()?;
+    Server::builder()
+        .add_service(ChainHooksServer::new(service))
+        .serve(addr)
+        .await?;
+    Ok(())
 }
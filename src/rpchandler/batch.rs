@@ -0,0 +1,287 @@
+use crate::transactionTypes::RelayerError;
+use alloy::primitives::B256;
+use dashmap::DashMap;
+use serde_json::{Value, json};
+use std::time::{Duration, Instant};
+
+/// Digests are flushed after this long when the next block's events don't arrive first
+pub const DEFAULT_DIGEST_WINDOW: Duration = Duration::from_secs(30);
+
+/// How matched events of a subscription are handed to its sinks
+#[derive(Clone, Debug)]
+pub enum DeliveryMode {
+    Immediate,
+    /// Accumulate events and flush when any of the set limits is hit, zero disables a limit
+    Batch {
+        max_events: usize,
+        max_bytes: usize,
+        window: Duration,
+    },
+    /// One payload per block with every match from that block
+    BlockDigest {
+        window: Duration,
+    },
+}
+
+impl DeliveryMode {
+    pub fn validate(self) -> Result<Self, RelayerError> {
+        match self {
+            DeliveryMode::Batch {
+                max_events,
+                max_bytes,
+                window,
+            } if max_events == 0 && max_bytes == 0 && window.is_zero() => {
+                Err(RelayerError::InvalidDeliveryMode(
+                    "batch needs a count, size or time limit".to_string(),
+                ))
+            }
+            DeliveryMode::BlockDigest { window } if window.is_zero() => {
+                Ok(DeliveryMode::BlockDigest {
+                    window: DEFAULT_DIGEST_WINDOW,
+                })
+            }
+            mode => Ok(mode),
+        }
+    }
+}
+
+struct PendingBatch {
+    ids: Vec<String>,
+    events: Vec<Value>,
+    bytes: usize,
+    opened: Instant,
+    block: Option<(u64, Option<B256>)>,
+}
+
+impl PendingBatch {
+    fn new(block: Option<(u64, Option<B256>)>) -> Self {
+        PendingBatch {
+            ids: Vec::new(),
+            events: Vec::new(),
+            bytes: 0,
+            opened: Instant::now(),
+            block,
+        }
+    }
+
+    /// Event ids of the batch joined by commas, and the batch body
+    fn flush(self, sub_id: &str) -> (String, Value) {
+        let ids = self.ids.join(",");
+        let payload = match self.block {
+            Some((number, hash)) => json!({
+                "subscription_id": sub_id,
+                "block_number": number,
+                "block_hash": hash.map(|h| h.to_string()),
+                "count": self.events.len(),
+                "events": self.events,
+            }),
+            None => json!({
+                "subscription_id": sub_id,
+                "count": self.events.len(),
+                "events": self.events,
+            }),
+        };
+        (ids, payload)
+    }
+}
+
+/// Per subscription accumulation of payloads for batched and digest delivery
+#[derive(Default)]
+pub struct Batcher {
+    modes: DashMap<String, DeliveryMode>,
+    pending: DashMap<String, PendingBatch>,
+}
+
+impl Batcher {
+    pub fn set_mode(&self, sub_id: String, mode: DeliveryMode) {
+        match mode {
+            DeliveryMode::Immediate => {
+                self.modes.remove(&sub_id);
+            }
+            mode => {
+                self.modes.insert(sub_id, mode);
+            }
+        }
+    }
+
+    /// Drops the subscription's mode and returns whatever was still pending
+    pub fn remove(&self, sub_id: &str) -> Option<(String, Value)> {
+        self.modes.remove(sub_id);
+        self.pending
+            .remove(sub_id)
+            .map(|(sub_id, batch)| batch.flush(&sub_id))
+    }
+
+    /// Adds a payload and returns the `(event ids, payload)` pairs that are ready to be delivered
+    pub fn push(
+        &self,
+        sub_id: &str,
+        event_id: String,
+        payload: Value,
+        block: Option<(u64, Option<B256>)>,
+    ) -> Vec<(String, Value)> {
+        let mode = match self.modes.get(sub_id) {
+            Some(mode) => mode.clone(),
+            None => return vec![(event_id, payload)],
+        };
+        let mut ready = Vec::new();
+
+        if let DeliveryMode::BlockDigest { .. } = mode {
+            // a new block closes the previous digest
+            let stale = match self.pending.get(sub_id) {
+                Some(batch) => batch.block.map(|b| b.0) != block.map(|b| b.0),
+                None => false,
+            };
+            if stale {
+                if let Some((_, batch)) = self.pending.remove(sub_id) {
+                    ready.push(batch.flush(sub_id));
+                }
+            }
+        }
+
+        let size = payload.to_string().len();
+        if let DeliveryMode::Batch { max_bytes, .. } = mode {
+            // flush first so a batch never grows past max_bytes, a single larger event goes out alone
+            let overflows = match self.pending.get(sub_id) {
+                Some(batch) => max_bytes > 0 && batch.bytes + size > max_bytes,
+                None => false,
+            };
+            if overflows {
+                if let Some((_, batch)) = self.pending.remove(sub_id) {
+                    ready.push(batch.flush(sub_id));
+                }
+            }
+        }
+
+        let full = {
+            let digest = matches!(mode, DeliveryMode::BlockDigest { .. });
+            let mut batch = self
+                .pending
+                .entry(sub_id.to_string())
+                .or_insert_with(|| PendingBatch::new(if digest { block } else { None }));
+            batch.bytes += size;
+            batch.ids.push(event_id);
+            batch.events.push(payload);
+            match mode {
+                DeliveryMode::Batch {
+                    max_events,
+                    max_bytes,
+                    ..
+                } => {
+                    (max_events > 0 && batch.events.len() >= max_events)
+                        || (max_bytes > 0 && batch.bytes >= max_bytes)
+                }
+                _ => false,
+            }
+        };
+        if full {
+            if let Some((_, batch)) = self.pending.remove(sub_id) {
+                ready.push(batch.flush(sub_id));
+            }
+        }
+        ready
+    }
+
+    /// Flushes batches whose time window has passed, as `(sub id, event ids, payload)`
+    pub fn expired(&self) -> Vec<(String, String, Value)> {
+        let mut due = Vec::new();
+        for entry in self.pending.iter() {
+            let window = match self.modes.get(entry.key()).map(|m| m.clone()) {
+                Some(DeliveryMode::Batch { window, .. }) => window,
+                Some(DeliveryMode::BlockDigest { window }) => window,
+                _ => Duration::ZERO,
+            };
+            if !window.is_zero() && entry.opened.elapsed() >= window {
+                due.push(entry.key().clone());
+            }
+        }
+        due.into_iter()
+            .filter_map(|sub_id| self.pending.remove(&sub_id))
+            .map(|(sub_id, batch)| {
+                let (ids, payload) = batch.flush(&sub_id);
+                (sub_id, ids, payload)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batcher(mode: DeliveryMode) -> Batcher {
+        let batcher = Batcher::default();
+        batcher.set_mode("sub".to_string(), mode);
+        batcher
+    }
+
+    fn event(n: usize, len: usize) -> (String, Value) {
+        (n.to_string(), Value::String("x".repeat(len)))
+    }
+
+    #[test]
+    fn batch_flushes_before_exceeding_max_bytes() {
+        // each payload serializes to 10 bytes with its quotes
+        let batcher = batcher(DeliveryMode::Batch {
+            max_events: 0,
+            max_bytes: 25,
+            window: Duration::ZERO,
+        });
+        let mut flushed = Vec::new();
+        for n in 0..5 {
+            let (id, payload) = event(n, 8);
+            flushed.extend(batcher.push("sub", id, payload, None));
+        }
+        let ids: Vec<&str> = flushed.iter().map(|(ids, _)| ids.as_str()).collect();
+        assert_eq!(ids, ["0,1", "2,3"]);
+        let (rest, _) = batcher.remove("sub").unwrap();
+        assert_eq!(rest, "4");
+    }
+
+    #[test]
+    fn oversized_event_goes_out_alone() {
+        let batcher = batcher(DeliveryMode::Batch {
+            max_events: 0,
+            max_bytes: 25,
+            window: Duration::ZERO,
+        });
+        let (id, payload) = event(0, 8);
+        assert!(batcher.push("sub", id, payload, None).is_empty());
+        let (id, payload) = event(1, 40);
+        let flushed = batcher.push("sub", id, payload, None);
+        let ids: Vec<&str> = flushed.iter().map(|(ids, _)| ids.as_str()).collect();
+        assert_eq!(ids, ["0", "1"]);
+        assert!(batcher.remove("sub").is_none());
+    }
+
+    #[test]
+    fn batch_flushes_at_max_events() {
+        let batcher = batcher(DeliveryMode::Batch {
+            max_events: 2,
+            max_bytes: 0,
+            window: Duration::ZERO,
+        });
+        let (id, payload) = event(0, 1);
+        assert!(batcher.push("sub", id, payload, None).is_empty());
+        let (id, payload) = event(1, 1);
+        let flushed = batcher.push("sub", id, payload, None);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].1["count"], 2);
+    }
+
+    #[test]
+    fn digest_closes_on_new_block() {
+        let batcher = batcher(DeliveryMode::BlockDigest {
+            window: DEFAULT_DIGEST_WINDOW,
+        });
+        let (id, payload) = event(0, 1);
+        assert!(batcher.push("sub", id, payload, Some((1, None))).is_empty());
+        let (id, payload) = event(1, 1);
+        assert!(batcher.push("sub", id, payload, Some((1, None))).is_empty());
+        let (id, payload) = event(2, 1);
+        let flushed = batcher.push("sub", id, payload, Some((2, None)));
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].0, "0,1");
+        assert_eq!(flushed[0].1["block_number"], 1);
+    }
+}
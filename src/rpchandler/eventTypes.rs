@@ -0,0 +1,712 @@
+use crate::rpchandler::rpc_types::RpcTypeError;
+use crate::rpchandler::scheduler::Schedule;
+use alloy::consensus::Transaction as _;
+use alloy::dyn_abi::{DynSolType, DynSolValue, EventExt, JsonAbiExt, Specifier};
+use alloy::json_abi::{Event, EventParam, Function, JsonAbi};
+use alloy::primitives::{Address, B256, Bytes, FixedBytes, U256, hex, keccak256};
+use alloy::rpc::types::{Header, Log, Transaction};
+use serde_json::{Map, Value, json};
+
+/// Single decoded event argument
+#[derive(Clone, Debug)]
+pub struct DecodedArg {
+    pub name: String,
+    pub sol_type: String,
+    pub value: DynSolValue,
+    pub indexed: bool,
+    pub position: usize,
+}
+
+/// Header fields of the block behind a trigger
+#[derive(Clone, Debug, Default)]
+pub struct BlockInfo {
+    pub number: u64,
+    pub hash: B256,
+    pub timestamp: u64,
+    pub base_fee: Option<u64>,
+}
+
+/// Transaction behind a trigger that isn't a log, e.g. a pending call
+#[derive(Clone, Debug, Default)]
+pub struct TxInfo {
+    pub from: Address,
+    pub to: Option<Address>,
+    pub value: U256,
+    pub selector: Option<FixedBytes<4>>,
+    pub input: Bytes,
+}
+
+/// Arguments `DecodedLog::from_transfer` fills, as an event so templates can be checked against them
+const NATIVE_TRANSFER_EVENT: &str =
+    "event NativeTransfer(address from, address to, uint256 value, bool internal)";
+/// Arguments `DecodedLog::from_schedule` fills
+const SCHEDULED_EVENT: &str = "event Scheduled(uint64 scheduledAt, uint64 firedAt, uint64 run)";
+
+/// Plain value transfer found while scanning a block
+#[derive(Clone, Debug)]
+pub struct NativeTransfer {
+    pub from: Address,
+    pub to: Address,
+    pub value: U256,
+    pub tx_hash: B256,
+    /// 0 for the transaction itself, internal calls are numbered from 1 in call order
+    pub index: u64,
+}
+
+/// Log matched by a subscription, decoded with the subscription's event ABI when one was given
+#[derive(Clone, Debug, Default)]
+pub struct DecodedLog {
+    pub sub_id: String,
+    pub event_name: String,
+    pub args: Vec<DecodedArg>,
+    pub address: Address,
+    pub block_number: Option<u64>,
+    pub block_hash: Option<B256>,
+    pub tx_hash: Option<B256>,
+    pub log_index: Option<u64>,
+    pub removed: bool,
+    /// Read by a `from_block` backfill rather than the live stream
+    pub historical: bool,
+    /// Why the log couldn't be decoded with the subscription's event, `args` is empty then
+    pub decode_error: Option<String>,
+    /// Set for block triggers
+    pub block: Option<BlockInfo>,
+    /// Set for transaction triggers
+    pub transaction: Option<TxInfo>,
+    /// Rendered payload template, if the subscription has one
+    pub payload: Option<Value>,
+}
+
+impl DecodedLog {
+    /// Builds the record for a log, decoding topics and data when `event` is known.
+    /// A log that doesn't fit the event is kept undecoded with `decode_error` set
+    pub fn from_log(sub_id: String, log: &Log, event: Option<&Event>) -> Self {
+        let mut decoded = DecodedLog {
+            sub_id,
+            event_name: String::new(),
+            args: Vec::new(),
+            address: log.address(),
+            block_number: log.block_number,
+            block_hash: log.block_hash,
+            tx_hash: log.transaction_hash,
+            log_index: log.log_index,
+            removed: log.removed,
+            historical: false,
+            decode_error: None,
+            block: None,
+            transaction: None,
+            payload: None,
+        };
+
+        let event = match event {
+            Some(event) => event,
+            None => return decoded,
+        };
+        match decode_args(log, event) {
+            Ok(args) => {
+                decoded.args = args;
+                decoded.event_name = event.name.clone();
+            }
+            Err(e) => decoded.decode_error = Some(e),
+        }
+        decoded
+    }
+
+    /// Trigger record of a new block, for `newHeads` subscriptions
+    pub fn from_header(sub_id: String, header: &Header) -> Self {
+        DecodedLog {
+            sub_id,
+            event_name: "NewBlock".to_string(),
+            block_number: Some(header.number),
+            block_hash: Some(header.hash),
+            block: Some(BlockInfo::from_header(header)),
+            ..Default::default()
+        }
+    }
+
+    /// Trigger record of a pending transaction, the calldata is decoded when `abi` has the called function
+    pub fn from_pending_tx(sub_id: String, tx: &Transaction, abi: Option<&JsonAbi>) -> Self {
+        let input = tx.input().clone();
+        let selector = input.get(..4).map(FixedBytes::<4>::from_slice);
+        let mut decoded = DecodedLog {
+            sub_id,
+            address: tx.to().unwrap_or_default(),
+            tx_hash: Some(*tx.inner.tx_hash()),
+            transaction: Some(TxInfo {
+                from: tx.inner.signer(),
+                to: tx.to(),
+                value: tx.value(),
+                selector,
+                input: input.clone(),
+            }),
+            ..Default::default()
+        };
+
+        let function = match (abi, selector) {
+            (Some(abi), Some(selector)) => abi.functions().find(|f| f.selector() == selector),
+            _ => None,
+        };
+        let function = match function {
+            Some(function) => function,
+            None => return decoded,
+        };
+        // calldata that doesn't match the ABI is still delivered, undecoded
+        if let Ok(values) = function.abi_decode_input(&input[4..]) {
+            for (position, (input, value)) in function.inputs.iter().zip(values).enumerate() {
+                let name = if input.name.is_empty() {
+                    format!("arg{position}")
+                } else {
+                    input.name.clone()
+                };
+                decoded.args.push(DecodedArg {
+                    name,
+                    sol_type: input.selector_type().to_string(),
+                    value,
+                    indexed: false,
+                    position,
+                });
+            }
+            decoded.event_name = function.name.clone();
+        }
+        decoded
+    }
+
+    /// Trigger record of a native transfer, with `from`, `to`, `value` and `internal` as arguments
+    pub fn from_transfer(sub_id: String, transfer: &NativeTransfer, header: &Header) -> Self {
+        let mut decoded = DecodedLog::from_header(sub_id, header);
+        let args = [
+            ("from", "address", DynSolValue::Address(transfer.from)),
+            ("to", "address", DynSolValue::Address(transfer.to)),
+            ("value", "uint256", DynSolValue::Uint(transfer.value, 256)),
+            ("internal", "bool", DynSolValue::Bool(transfer.index > 0)),
+        ];
+        for (position, (name, sol_type, value)) in args.into_iter().enumerate() {
+            decoded.args.push(DecodedArg {
+                name: name.to_string(),
+                sol_type: sol_type.to_string(),
+                value,
+                indexed: false,
+                position,
+            });
+        }
+        decoded.event_name = "NativeTransfer".to_string();
+        decoded.address = transfer.to;
+        decoded.tx_hash = Some(transfer.tx_hash);
+        decoded.log_index = Some(transfer.index);
+        decoded
+    }
+
+    /// Trigger record of a schedule run, with the planned and actual run times and the run number as arguments
+    pub fn from_schedule(schedule: &Schedule, fired_at: u64) -> Self {
+        let run = schedule.runs + 1;
+        let args = [
+            ("scheduledAt", schedule.next_run),
+            ("firedAt", fired_at),
+            ("run", run),
+        ];
+        let mut decoded = DecodedLog {
+            sub_id: schedule.id.clone(),
+            event_name: "Scheduled".to_string(),
+            log_index: Some(run),
+            ..Default::default()
+        };
+        for (position, (name, value)) in args.into_iter().enumerate() {
+            decoded.args.push(DecodedArg {
+                name: name.to_string(),
+                sol_type: "uint64".to_string(),
+                value: DynSolValue::Uint(U256::from(value), 64),
+                indexed: false,
+                position,
+            });
+        }
+        decoded
+    }
+
+    /// Identifies the log across deliveries, `<tx hash>:<log index>`,
+    /// `<block>:<index>` for block triggers and `<subscription>:<run>` for schedules
+    pub fn event_id(&self) -> String {
+        match (self.tx_hash, self.block_number) {
+            (Some(hash), _) => format!("{hash}:{}", self.log_index.unwrap_or_default()),
+            (None, Some(block)) => format!("{block}:{}", self.log_index.unwrap_or_default()),
+            (None, None) => format!("{}:{}", self.sub_id, self.log_index.unwrap_or_default()),
+        }
+    }
+
+    pub fn arg(&self, name: &str) -> Option<&DecodedArg> {
+        self.args.iter().find(|a| a.name == name)
+    }
+
+    /// JSON body used when a subscription has no payload template
+    pub fn to_json(&self) -> Value {
+        let mut args = Map::new();
+        for arg in &self.args {
+            args.insert(arg.name.clone(), sol_value_to_json(&arg.value));
+        }
+        let mut body = json!({
+            "subscription_id": self.sub_id,
+            "event": self.event_name,
+            "args": args,
+            "address": self.address.to_string(),
+            "block_number": self.block_number,
+            "block_hash": self.block_hash.map(|h| h.to_string()),
+            "tx_hash": self.tx_hash.map(|h| h.to_string()),
+            "log_index": self.log_index,
+            "removed": self.removed,
+            "historical": self.historical,
+        });
+        if let Some(e) = &self.decode_error {
+            body["decode_error"] = Value::String(e.clone());
+        }
+        if let Some(block) = &self.block {
+            body["block"] = block.to_json();
+        }
+        if let Some(tx) = &self.transaction {
+            body["transaction"] = tx.to_json();
+        }
+        body
+    }
+}
+
+impl TxInfo {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "from": self.from.to_string(),
+            "to": self.to.map(|a| a.to_string()),
+            "value": self.value.to_string(),
+            "selector": self.selector.map(|s| s.to_string()),
+            "input": self.input.to_string(),
+        })
+    }
+}
+
+impl BlockInfo {
+    pub fn from_header(header: &Header) -> Self {
+        BlockInfo {
+            number: header.number,
+            hash: header.hash,
+            timestamp: header.timestamp,
+            base_fee: header.base_fee_per_gas,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "number": self.number,
+            "hash": self.hash.to_string(),
+            "timestamp": self.timestamp,
+            "base_fee": self.base_fee,
+        })
+    }
+}
+
+/// Decodes a log's topics and data in the order of the event inputs
+fn decode_args(log: &Log, event: &Event) -> Result<Vec<DecodedArg>, String> {
+    let values = match event.decode_log(log.data()) {
+        Ok(values) => values,
+        Err(e) => return Err(format!("{}: {e}", RpcTypeError::LogDecodeError)),
+    };
+    let mut indexed = values.indexed.into_iter();
+    let mut body = values.body.into_iter();
+    let mut args = Vec::new();
+    for (position, input) in event.inputs.iter().enumerate() {
+        let value = if input.indexed {
+            indexed.next()
+        } else {
+            body.next()
+        };
+        let value = match value {
+            Some(v) => v,
+            None => return Err(RpcTypeError::LogDecodeError.to_string()),
+        };
+        let name = if input.name.is_empty() {
+            format!("arg{position}")
+        } else {
+            input.name.clone()
+        };
+        args.push(DecodedArg {
+            name,
+            sol_type: input.selector_type().to_string(),
+            value,
+            indexed: input.indexed,
+            position,
+        });
+    }
+    Ok(args)
+}
+
+/// Converts a decoded value to JSON, integers are kept as decimal strings so they survive JS clients
+pub fn sol_value_to_json(value: &DynSolValue) -> Value {
+    match value {
+        DynSolValue::Bool(b) => Value::Bool(*b),
+        DynSolValue::Int(i, _) => Value::String(i.to_string()),
+        DynSolValue::Uint(u, _) => Value::String(u.to_string()),
+        DynSolValue::FixedBytes(word, size) => Value::String(hex::encode_prefixed(&word[..*size])),
+        DynSolValue::Address(addr) => Value::String(addr.to_string()),
+        DynSolValue::Function(func) => Value::String(func.to_string()),
+        DynSolValue::Bytes(bytes) => Value::String(hex::encode_prefixed(bytes)),
+        DynSolValue::String(s) => Value::String(s.clone()),
+        DynSolValue::Array(values)
+        | DynSolValue::FixedArray(values)
+        | DynSolValue::Tuple(values) => {
+            Value::Array(values.iter().map(sol_value_to_json).collect())
+        }
+        other => Value::String(format!("{other:?}")),
+    }
+}
+
+/// Plain string form of a decoded value
+pub fn sol_value_to_string(value: &DynSolValue) -> String {
+    match sol_value_to_json(value) {
+        Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// Reads the event ABI given at Subscribe time.
+/// Accepts a single event item or a full contract ABI, in which case the event is picked by signature
+pub fn parse_event_abi(abi: &str, event_signature: &str) -> Result<Event, RpcTypeError> {
+    if let Ok(event) = serde_json::from_str::<Event>(abi) {
+        return Ok(event);
+    }
+    let contract: JsonAbi = match serde_json::from_str(abi) {
+        Ok(contract) => contract,
+        Err(_) => return Err(RpcTypeError::InvalidEventAbi),
+    };
+
+    let signature: String = event_signature
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let name = signature.split('(').next().unwrap_or_default();
+    let candidates = match contract.event(name) {
+        Some(events) => events,
+        None => return Err(RpcTypeError::InvalidEventAbi),
+    };
+    if candidates.len() == 1 && !signature.contains('(') {
+        return Ok(candidates[0].clone());
+    }
+    candidates
+        .iter()
+        .find(|e| e.signature() == signature)
+        .cloned()
+        .ok_or(RpcTypeError::InvalidEventAbi)
+}
+
+/// Reads a human readable event signature, with or without the `event` keyword, parameter names and `indexed` markers.
+/// Types are normalized, so `uint` becomes `uint256`, and the event selector is the topic0 logs are filtered by
+pub fn parse_event_signature(signature: &str) -> Result<Event, RpcTypeError> {
+    let signature = signature.trim();
+    let event = match Event::parse(signature) {
+        Ok(event) => event,
+        Err(_) => return Err(RpcTypeError::InvalidEventSignature(signature.to_string())),
+    };
+    if event.anonymous {
+        return Err(RpcTypeError::InvalidEventSignature(format!(
+            "{signature} is anonymous and has no topic0"
+        )));
+    }
+    for input in &event.inputs {
+        if let Err(e) = input.resolve() {
+            return Err(RpcTypeError::InvalidEventSignature(format!(
+                "{signature}: {e}"
+            )));
+        }
+    }
+    if event.inputs.iter().filter(|i| i.indexed).count() > 3 {
+        return Err(RpcTypeError::InvalidEventSignature(format!(
+            "{signature} has more than 3 indexed parameters"
+        )));
+    }
+    Ok(event)
+}
+
+/// Resolves the event of a log subscription into its canonical signature, which gives the topic0 filter,
+/// and the event logs are decoded with.
+/// The ABI is optional when the signature is a full declaration, if both are given they must describe the same event.
+/// A bare `Transfer(address,address,uint256)`, without parameter names or `indexed` markers, doesn't say which
+/// inputs are indexed, so it only selects topic0 and its logs are delivered undecoded
+pub fn log_event(signature: &str, abi: &str) -> Result<(String, Option<Event>), RpcTypeError> {
+    let declared = match signature.trim() {
+        "" => None,
+        signature => Some(parse_event_signature(signature)?),
+    };
+    let canonical = declared.as_ref().map(|e| e.signature()).unwrap_or_default();
+    if abi.is_empty() {
+        return Ok(match declared {
+            Some(event) if event.inputs.iter().any(|i| i.indexed || !i.name.is_empty()) => {
+                (canonical, Some(event))
+            }
+            _ => (canonical, None),
+        });
+    }
+    let event = parse_event_abi(abi, &canonical)?;
+    if declared.is_some_and(|d| d.selector() != event.selector()) {
+        return Err(RpcTypeError::InvalidEventSignature(
+            "event ABI doesn't match the event signature".to_string(),
+        ));
+    }
+    Ok((event.signature(), Some(event)))
+}
+
+/// The fields of a native transfer trigger, see `DecodedLog::from_transfer`
+pub fn transfer_event() -> Event {
+    Event::parse(NATIVE_TRANSFER_EVENT).expect("valid event")
+}
+
+/// The fields of a schedule run, see `DecodedLog::from_schedule`
+pub fn schedule_event() -> Event {
+    Event::parse(SCHEDULED_EVENT).expect("valid event")
+}
+
+/// Reads a 4 byte function selector, given as hex or as a function signature like `transfer(address,uint256)`
+/// or `function transfer(address to, uint256 amount)`. Signatures also give the function calldata is decoded with
+pub fn parse_selector(selector: &str) -> Result<(FixedBytes<4>, Option<Function>), RpcTypeError> {
+    let selector = selector.trim();
+    if selector.contains('(') {
+        return match Function::parse(selector) {
+            Ok(function) => Ok((function.selector(), Some(function))),
+            Err(_) => Err(RpcTypeError::InvalidSelector(selector.to_string())),
+        };
+    }
+    match selector.parse::<FixedBytes<4>>() {
+        Ok(selector) => Ok((selector, None)),
+        Err(_) => Err(RpcTypeError::InvalidSelector(selector.to_string())),
+    }
+}
+
+/// Reads the ABI of a pending transaction subscription, a single function item or a full contract ABI
+pub fn parse_function_abi(abi: &str) -> Result<JsonAbi, RpcTypeError> {
+    if let Ok(function) = serde_json::from_str::<Function>(abi) {
+        let mut contract = JsonAbi::new();
+        contract
+            .functions
+            .insert(function.name.clone(), vec![function]);
+        return Ok(contract);
+    }
+    serde_json::from_str(abi).map_err(|_| RpcTypeError::InvalidFunctionAbi)
+}
+
+/// The inputs of `function` as an event without indexed inputs, so the `event.*` fields of a decoded
+/// pending transaction type check like those of a log
+pub fn calldata_event(function: &Function) -> Event {
+    Event {
+        name: function.name.clone(),
+        inputs: function
+            .inputs
+            .iter()
+            .map(|input| EventParam {
+                ty: input.ty.clone(),
+                name: input.name.clone(),
+                indexed: false,
+                components: input.components.clone(),
+                internal_type: input.internal_type.clone(),
+            })
+            .collect(),
+        anonymous: false,
+    }
+}
+
+/// Encodes the accepted values of indexed topic `position` (1 to 3) for the log filter.
+/// Values are typed by the matching indexed input of `event`, without an ABI only raw 32 byte topics are accepted.
+pub fn topic_filter(
+    event: Option<&Event>,
+    position: usize,
+    values: &[String],
+) -> Result<Vec<B256>, RpcTypeError> {
+    let invalid = |msg: String| RpcTypeError::InvalidTopicFilter(format!("topic{position}: {msg}"));
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let event = match event {
+        Some(event) => event,
+        None => {
+            return values
+                .iter()
+                .map(|v| {
+                    v.parse::<B256>()
+                        .map_err(|_| invalid(format!("{v} is not a 32 byte topic")))
+                })
+                .collect();
+        }
+    };
+    if event.anonymous {
+        return Err(invalid(
+            "anonymous events can't be filtered by topic".to_string(),
+        ));
+    }
+    let input = match event.inputs.iter().filter(|i| i.indexed).nth(position - 1) {
+        Some(input) => input,
+        None => {
+            return Err(invalid(format!(
+                "{} has no indexed input {position}",
+                event.name
+            )));
+        }
+    };
+    let ty: DynSolType = match input.resolve() {
+        Ok(ty) => ty,
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+
+    let mut topics = Vec::new();
+    for v in values {
+        let value = match ty.coerce_str(v) {
+            Ok(value) => value,
+            Err(_) => return Err(invalid(format!("{v} is not a valid {}", input.ty))),
+        };
+        // indexed strings and bytes are stored as their hash
+        let topic = match &value {
+            DynSolValue::String(s) => keccak256(s.as_bytes()),
+            DynSolValue::Bytes(b) => keccak256(b),
+            other => match other.as_word() {
+                Some(word) => word,
+                None => {
+                    return Err(invalid(format!(
+                        "filtering on {} is not supported",
+                        input.ty
+                    )));
+                }
+            },
+        };
+        topics.push(topic);
+    }
+    Ok(topics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{LogData, address};
+
+    const ERC20_TRANSFER: &str =
+        "event Transfer(address indexed from, address indexed to, uint256 value)";
+
+    fn word(value: u64) -> B256 {
+        B256::from(U256::from(value))
+    }
+
+    fn log(topics: Vec<B256>, data: Vec<u8>) -> Log {
+        Log {
+            inner: alloy::primitives::Log {
+                address: address!("0x00000000000000000000000000000000000000aa"),
+                data: LogData::new_unchecked(topics, Bytes::from(data)),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decodes_log_matching_the_event() {
+        let event = parse_event_signature(ERC20_TRANSFER).unwrap();
+        let log = log(vec![event.selector(), word(1), word(2)], word(500).to_vec());
+        let decoded = DecodedLog::from_log("sub".to_string(), &log, Some(&event));
+        assert!(decoded.decode_error.is_none());
+        assert_eq!(decoded.event_name, "Transfer");
+        assert_eq!(decoded.args.len(), 3);
+        assert_eq!(
+            decoded.args[2].value,
+            DynSolValue::Uint(U256::from(500), 256)
+        );
+    }
+
+    #[test]
+    fn log_not_fitting_the_event_is_kept_undecoded() {
+        // ERC-721 Transfer shares topic0 with ERC-20 but indexes the token id
+        let event = parse_event_signature(ERC20_TRANSFER).unwrap();
+        let log = log(
+            vec![event.selector(), word(1), word(2), word(3)],
+            Vec::new(),
+        );
+        let decoded = DecodedLog::from_log("sub".to_string(), &log, Some(&event));
+        assert!(decoded.decode_error.is_some());
+        assert!(decoded.args.is_empty());
+        assert!(decoded.to_json()["decode_error"].is_string());
+    }
+
+    #[test]
+    fn bare_signature_selects_topic0_only() {
+        let (signature, event) = log_event("Transfer(address,address,uint256)", "").unwrap();
+        assert_eq!(signature, "Transfer(address,address,uint256)");
+        assert!(event.is_none());
+        // without a decoding event raw topics are accepted for topic1 to topic3
+        let topics = topic_filter(event.as_ref(), 3, &[word(3).to_string()]).unwrap();
+        assert_eq!(topics, vec![word(3)]);
+
+        let (signature, event) = log_event(ERC20_TRANSFER, "").unwrap();
+        assert_eq!(signature, "Transfer(address,address,uint256)");
+        assert!(event.is_some());
+    }
+
+    #[test]
+    fn trigger_events_describe_the_runtime_args() {
+        let transfer = NativeTransfer {
+            from: Address::repeat_byte(1),
+            to: Address::repeat_byte(2),
+            value: U256::from(5),
+            tx_hash: B256::ZERO,
+            index: 1,
+        };
+        let decoded = DecodedLog::from_transfer("sub".to_string(), &transfer, &Header::default());
+        let event = transfer_event();
+        let names: Vec<_> = event.inputs.iter().map(|i| i.name.as_str()).collect();
+        let args: Vec<_> = decoded.args.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, args);
+        let template =
+            r#"{"from": "{{event.from}}", "to": "{{event.to}}", "value": "{{event.value}}"}"#;
+        assert!(
+            crate::rpchandler::template::PayloadTemplate::parse(template, Some(&event)).is_ok()
+        );
+        assert!(crate::rpchandler::template::PayloadTemplate::parse(template, None).is_err());
+
+        let event = schedule_event();
+        let names: Vec<_> = event.inputs.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["scheduledAt", "firedAt", "run"]);
+    }
+
+    #[test]
+    fn named_declaration_without_indexed_inputs_is_decoded() {
+        for signature in ["event Deposit(uint256 amount)", "Deposit(uint256 amount)"] {
+            let (canonical, event) = log_event(signature, "").unwrap();
+            assert_eq!(canonical, "Deposit(uint256)");
+            let event = event.unwrap();
+            let log = log(vec![event.selector()], word(7).to_vec());
+            let decoded = DecodedLog::from_log("sub".to_string(), &log, Some(&event));
+            assert!(decoded.decode_error.is_none());
+            assert_eq!(decoded.args[0].name, "amount");
+            assert!(
+                crate::rpchandler::expr::Expr::predicate(
+                    "event.amount > 1",
+                    &crate::rpchandler::expr::event_types(Some(&event))
+                )
+                .is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn selector_signature_types_calldata_fields() {
+        let (selector, function) =
+            parse_selector("function transfer(address to, uint256 amount)").unwrap();
+        assert_eq!(selector, parse_selector("0xa9059cbb").unwrap().0);
+        let function = function.unwrap();
+        assert_eq!(
+            parse_selector("transfer(address,uint256)").unwrap().0,
+            selector
+        );
+
+        let event = calldata_event(&function);
+        assert_eq!(event.name, "transfer");
+        assert!(event.inputs.iter().all(|i| !i.indexed));
+        assert!(
+            crate::rpchandler::transactionTypes::check_param("event.amount", Some(&event)).is_ok()
+        );
+        assert!(
+            crate::rpchandler::transactionTypes::check_param("event.value", Some(&event)).is_err()
+        );
+
+        let abi = parse_function_abi(&serde_json::to_string(&function).unwrap()).unwrap();
+        assert_eq!(abi.functions().count(), 1);
+        assert!(parse_function_abi("{").is_err());
+    }
+}
@@ -0,0 +1,1050 @@
+use crate::rpchandler::eventTypes::DecodedLog;
+use alloy::dyn_abi::{DynSolType, DynSolValue, Specifier};
+use alloy::json_abi::Event;
+use alloy::primitives::{Address, B256, Bytes, I256, U256, hex, keccak256};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Errors for expression parsing, checking and evaluation
+#[derive(Error, Debug)]
+pub enum ExprError {
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("Type error: {0}")]
+    Type(String),
+    #[error("Unknown variable {0}")]
+    UnknownVariable(String),
+    #[error("Evaluation error: {0}")]
+    Eval(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExprType {
+    Uint,
+    Int,
+    Bool,
+    Address,
+    Bytes,
+    String,
+}
+
+impl ExprType {
+    fn is_numeric(self) -> bool {
+        matches!(self, ExprType::Uint | ExprType::Int)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprValue {
+    /// Value and declared bit width, the width only matters to `abi.encodePacked`
+    Uint(U256, usize),
+    Int(I256, usize),
+    Bool(bool),
+    Address(Address),
+    Bytes(Bytes),
+    String(String),
+}
+
+impl ExprValue {
+    pub fn ty(&self) -> ExprType {
+        match self {
+            ExprValue::Uint(..) => ExprType::Uint,
+            ExprValue::Int(..) => ExprType::Int,
+            ExprValue::Bool(_) => ExprType::Bool,
+            ExprValue::Address(_) => ExprType::Address,
+            ExprValue::Bytes(_) => ExprType::Bytes,
+            ExprValue::String(_) => ExprType::String,
+        }
+    }
+
+    /// Converts a decoded ABI value, arrays and tuples have no expression type
+    pub fn from_sol(value: &DynSolValue) -> Option<Self> {
+        match value {
+            DynSolValue::Uint(u, bits) => Some(ExprValue::Uint(*u, *bits)),
+            DynSolValue::Int(i, bits) => Some(ExprValue::Int(*i, *bits)),
+            DynSolValue::Bool(b) => Some(ExprValue::Bool(*b)),
+            DynSolValue::Address(a) => Some(ExprValue::Address(*a)),
+            DynSolValue::FixedBytes(word, size) => {
+                Some(ExprValue::Bytes(Bytes::copy_from_slice(&word[..*size])))
+            }
+            DynSolValue::Bytes(b) => Some(ExprValue::Bytes(Bytes::from(b.clone()))),
+            DynSolValue::String(s) => Some(ExprValue::String(s.clone())),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<I256> {
+        match self {
+            ExprValue::Int(i, _) => Some(*i),
+            ExprValue::Uint(u, _) => I256::try_from(*u).ok(),
+            _ => None,
+        }
+    }
+
+    /// Raw bytes of byte and string values
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            ExprValue::Bytes(b) => Some(b),
+            ExprValue::String(s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Converts a result to an ABI input, integers are range checked against the input width
+    /// and shorter byte strings are right padded into `bytesN`
+    pub fn to_sol(&self, ty: &DynSolType) -> Result<DynSolValue, ExprError> {
+        let out_of_range = || ExprError::Eval(format!("{self:?} doesn't fit {ty}"));
+        match (self, ty) {
+            (ExprValue::Uint(..) | ExprValue::Int(..), DynSolType::Uint(bits)) => {
+                let u = match self {
+                    ExprValue::Uint(u, _) => *u,
+                    ExprValue::Int(i, _) if !i.is_negative() => i.into_raw(),
+                    _ => return Err(out_of_range()),
+                };
+                match u.bit_len() <= *bits {
+                    true => Ok(DynSolValue::Uint(u, *bits)),
+                    false => Err(out_of_range()),
+                }
+            }
+            (ExprValue::Uint(..) | ExprValue::Int(..), DynSolType::Int(bits)) => {
+                let i = self.as_int().ok_or_else(out_of_range)?;
+                let fits = *bits == 256 || {
+                    let max = I256::from_raw(U256::from(1) << (bits - 1));
+                    i < max && i >= -max
+                };
+                match fits {
+                    true => Ok(DynSolValue::Int(i, *bits)),
+                    false => Err(out_of_range()),
+                }
+            }
+            (ExprValue::Bool(b), DynSolType::Bool) => Ok(DynSolValue::Bool(*b)),
+            (ExprValue::Address(a), DynSolType::Address) => Ok(DynSolValue::Address(*a)),
+            (ExprValue::Bytes(_) | ExprValue::String(_), DynSolType::Bytes) => Ok(
+                DynSolValue::Bytes(self.as_bytes().unwrap_or_default().to_vec()),
+            ),
+            (ExprValue::Bytes(b), DynSolType::FixedBytes(size)) if b.len() <= *size => {
+                let mut word = B256::ZERO;
+                word[..b.len()].copy_from_slice(b);
+                Ok(DynSolValue::FixedBytes(word, *size))
+            }
+            (ExprValue::String(s), DynSolType::String) => Ok(DynSolValue::String(s.clone())),
+            _ => Err(out_of_range()),
+        }
+    }
+}
+
+/// Whether a value of type `ty` can be passed as an ABI input of type `input`, ranges are checked when converting
+pub fn accepts(input: &DynSolType, ty: ExprType) -> bool {
+    match input {
+        DynSolType::Uint(_) | DynSolType::Int(_) => ty.is_numeric(),
+        DynSolType::Bool => ty == ExprType::Bool,
+        DynSolType::Address => ty == ExprType::Address,
+        DynSolType::Bytes => matches!(ty, ExprType::Bytes | ExprType::String),
+        DynSolType::FixedBytes(_) => ty == ExprType::Bytes,
+        DynSolType::String => ty == ExprType::String,
+        _ => false,
+    }
+}
+
+/// Maps an ABI type to its expression type, indexed dynamic values are only available as their hash
+pub fn sol_type(ty: &DynSolType, indexed: bool) -> Option<ExprType> {
+    match ty {
+        DynSolType::Uint(_) => Some(ExprType::Uint),
+        DynSolType::Int(_) => Some(ExprType::Int),
+        DynSolType::Bool => Some(ExprType::Bool),
+        DynSolType::Address => Some(ExprType::Address),
+        DynSolType::FixedBytes(_) => Some(ExprType::Bytes),
+        _ if indexed => Some(ExprType::Bytes),
+        DynSolType::Bytes => Some(ExprType::Bytes),
+        DynSolType::String => Some(ExprType::String),
+        _ => None,
+    }
+}
+
+/// Variables of an event log, both as `event.<name>` and bare `<name>`
+pub fn event_types(event: Option<&Event>) -> HashMap<String, ExprType> {
+    let mut types = HashMap::from([
+        ("log.address".to_string(), ExprType::Address),
+        ("log.blockNumber".to_string(), ExprType::Uint),
+        ("log.blockHash".to_string(), ExprType::Bytes),
+        ("log.txHash".to_string(), ExprType::Bytes),
+        ("log.logIndex".to_string(), ExprType::Uint),
+        ("log.removed".to_string(), ExprType::Bool),
+    ]);
+    if let Some(event) = event {
+        for (i, input) in event.inputs.iter().enumerate() {
+            let ty = match input
+                .resolve()
+                .ok()
+                .and_then(|t| sol_type(&t, input.indexed))
+            {
+                Some(ty) => ty,
+                None => continue,
+            };
+            let name = if input.name.is_empty() {
+                format!("arg{i}")
+            } else {
+                input.name.clone()
+            };
+            types.insert(format!("event.{name}"), ty);
+            types.insert(name, ty);
+        }
+    }
+    types
+}
+
+/// Variables of relayer action parameters, the event variables plus the trigger's transaction and block
+pub fn param_types(event: Option<&Event>) -> HashMap<String, ExprType> {
+    let mut types = event_types(event);
+    types.insert("tx.hash".to_string(), ExprType::Bytes);
+    for (field, ty) in [
+        ("number", ExprType::Uint),
+        ("hash", ExprType::Bytes),
+        ("timestamp", ExprType::Uint),
+        ("baseFee", ExprType::Uint),
+    ] {
+        types.insert(format!("block.{field}"), ty);
+    }
+    types
+}
+
+pub fn param_values(log: &DecodedLog) -> HashMap<String, ExprValue> {
+    let mut values = event_values(log);
+    if let Some(h) = log.tx_hash {
+        values.insert(
+            "tx.hash".to_string(),
+            ExprValue::Bytes(Bytes::copy_from_slice(h.as_slice())),
+        );
+    }
+    if let Some(block) = &log.block {
+        values.insert(
+            "block.number".to_string(),
+            ExprValue::Uint(U256::from(block.number), 256),
+        );
+        values.insert(
+            "block.hash".to_string(),
+            ExprValue::Bytes(Bytes::copy_from_slice(block.hash.as_slice())),
+        );
+        values.insert(
+            "block.timestamp".to_string(),
+            ExprValue::Uint(U256::from(block.timestamp), 256),
+        );
+        if let Some(fee) = block.base_fee {
+            values.insert(
+                "block.baseFee".to_string(),
+                ExprValue::Uint(U256::from(fee), 256),
+            );
+        }
+    }
+    values
+}
+
+pub fn event_values(log: &DecodedLog) -> HashMap<String, ExprValue> {
+    let mut values = HashMap::from([
+        ("log.address".to_string(), ExprValue::Address(log.address)),
+        ("log.removed".to_string(), ExprValue::Bool(log.removed)),
+    ]);
+    if let Some(n) = log.block_number {
+        values.insert(
+            "log.blockNumber".to_string(),
+            ExprValue::Uint(U256::from(n), 256),
+        );
+    }
+    if let Some(h) = log.block_hash {
+        values.insert(
+            "log.blockHash".to_string(),
+            ExprValue::Bytes(Bytes::copy_from_slice(h.as_slice())),
+        );
+    }
+    if let Some(h) = log.tx_hash {
+        values.insert(
+            "log.txHash".to_string(),
+            ExprValue::Bytes(Bytes::copy_from_slice(h.as_slice())),
+        );
+    }
+    if let Some(i) = log.log_index {
+        values.insert(
+            "log.logIndex".to_string(),
+            ExprValue::Uint(U256::from(i), 256),
+        );
+    }
+    for arg in &log.args {
+        if let Some(v) = ExprValue::from_sol(&arg.value) {
+            values.insert(format!("event.{}", arg.name), v.clone());
+            values.insert(arg.name.clone(), v);
+        }
+    }
+    values
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl BinOp {
+    fn is_arithmetic(self) -> bool {
+        matches!(
+            self,
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod
+        )
+    }
+}
+
+/// Built in functions, `abi.encodePacked` packs integers at their declared width like Solidity,
+/// literals and arithmetic results count as 256 bit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Func {
+    Min,
+    Max,
+    Keccak256,
+    EncodePacked,
+    Concat,
+}
+
+impl Func {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Func::Min),
+            "max" => Some(Func::Max),
+            "keccak256" => Some(Func::Keccak256),
+            "abi.encodePacked" => Some(Func::EncodePacked),
+            "concat" => Some(Func::Concat),
+            _ => None,
+        }
+    }
+
+    fn arity_ok(self, args: usize) -> bool {
+        match self {
+            Func::Min | Func::Max => args == 2,
+            Func::Keccak256 => args == 1,
+            Func::EncodePacked | Func::Concat => args > 0,
+        }
+    }
+}
+
+/// Ether denominations accepted after a number literal, with their decimals
+const UNITS: [(&str, usize); 3] = [("wei", 0), ("gwei", 9), ("ether", 18)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Not,
+    Neg,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(ExprValue),
+    Var(String),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(String),
+    Hex(String),
+    Str(String),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+const OPERATORS: [&str; 14] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "!", "-", "+", "*", "/", "%",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, ExprError> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '(' || c == ')' || c == ',' {
+            tokens.push(match c {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                _ => Token::Comma,
+            });
+            i += 1;
+            continue;
+        }
+        if c == '"' || c == '\'' {
+            let start = i + 1;
+            let end = match chars[start..].iter().position(|&x| x == c) {
+                Some(p) => start + p,
+                None => return Err(ExprError::Parse("unterminated string".to_string())),
+            };
+            tokens.push(Token::Str(chars[start..end].iter().collect()));
+            i = end + 1;
+            continue;
+        }
+        if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            let lit: String = chars[start..i].iter().filter(|&&x| x != '_').collect();
+            if let Some(h) = lit.strip_prefix("0x") {
+                tokens.push(Token::Hex(h.to_string()));
+            } else {
+                tokens.push(Token::Num(lit));
+            }
+            continue;
+        }
+        if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            continue;
+        }
+        let rest: String = chars[i..].iter().take(2).collect();
+        match OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            Some(op) => {
+                tokens.push(Token::Op(op));
+                i += op.len();
+            }
+            None => return Err(ExprError::Parse(format!("unexpected character '{c}'"))),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.peek() == Some(&Token::Op(Self::op(op))) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn op(op: &str) -> &'static str {
+        OPERATORS.iter().find(|o| **o == op).copied().unwrap_or("")
+    }
+
+    fn or(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.and()?;
+        while self.eat_op("||") {
+            let rhs = self.and()?;
+            lhs = Expr::Binary(BinOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.comparison()?;
+        while self.eat_op("&&") {
+            let rhs = self.comparison()?;
+            lhs = Expr::Binary(BinOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExprError> {
+        let lhs = self.additive()?;
+        let ops = [
+            ("==", BinOp::Eq),
+            ("!=", BinOp::Ne),
+            ("<=", BinOp::Le),
+            (">=", BinOp::Ge),
+            ("<", BinOp::Lt),
+            (">", BinOp::Gt),
+        ];
+        for (tok, op) in ops {
+            if self.eat_op(tok) {
+                let rhs = self.additive()?;
+                return Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn additive(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.multiplicative()?;
+        loop {
+            let op = if self.eat_op("+") {
+                BinOp::Add
+            } else if self.eat_op("-") {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.multiplicative()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat_op("*") {
+                BinOp::Mul
+            } else if self.eat_op("/") {
+                BinOp::Div
+            } else if self.eat_op("%") {
+                BinOp::Mod
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat_op("!") {
+            return Ok(Expr::Unary(UnOp::Not, Box::new(self.unary()?)));
+        }
+        if self.eat_op("-") {
+            return Ok(Expr::Unary(UnOp::Neg, Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        let token = match self.tokens.get(self.pos).cloned() {
+            Some(token) => token,
+            None => return Err(ExprError::Parse("unexpected end of expression".to_string())),
+        };
+        self.pos += 1;
+        match token {
+            Token::LParen => {
+                let inner = self.or()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(ExprError::Parse("missing ')'".to_string()));
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            Token::Num(n) => {
+                let unit = match self.peek() {
+                    Some(Token::Ident(id)) => UNITS.iter().find(|(unit, _)| unit == id),
+                    _ => None,
+                };
+                let decimals = match unit {
+                    Some((_, decimals)) => {
+                        self.pos += 1;
+                        *decimals
+                    }
+                    None => 0,
+                };
+                Ok(Expr::Literal(ExprValue::Uint(
+                    parse_number(&n, decimals)?,
+                    256,
+                )))
+            }
+            Token::Hex(h) => {
+                let bytes = match hex::decode(&h) {
+                    Ok(bytes) => bytes,
+                    Err(_) => return Err(ExprError::Parse(format!("invalid hex 0x{h}"))),
+                };
+                if bytes.len() == 20 {
+                    Ok(Expr::Literal(ExprValue::Address(Address::from_slice(
+                        &bytes,
+                    ))))
+                } else {
+                    Ok(Expr::Literal(ExprValue::Bytes(Bytes::from(bytes))))
+                }
+            }
+            Token::Str(s) => Ok(Expr::Literal(ExprValue::String(s))),
+            Token::Ident(id) if self.peek() == Some(&Token::LParen) => {
+                let func = match Func::parse(&id) {
+                    Some(func) => func,
+                    None => return Err(ExprError::Parse(format!("unknown function {id}"))),
+                };
+                self.pos += 1;
+                let mut args = Vec::new();
+                while self.peek() != Some(&Token::RParen) {
+                    if !args.is_empty() {
+                        if self.peek() != Some(&Token::Comma) {
+                            return Err(ExprError::Parse(format!("expected ',' in {id}()")));
+                        }
+                        self.pos += 1;
+                    }
+                    args.push(self.or()?);
+                }
+                self.pos += 1;
+                if !func.arity_ok(args.len()) {
+                    return Err(ExprError::Parse(format!(
+                        "wrong number of arguments for {id}()"
+                    )));
+                }
+                Ok(Expr::Call(func, args))
+            }
+            Token::Ident(id) => match id.as_str() {
+                "true" => Ok(Expr::Literal(ExprValue::Bool(true))),
+                "false" => Ok(Expr::Literal(ExprValue::Bool(false))),
+                _ => Ok(Expr::Var(id)),
+            },
+            other => Err(ExprError::Parse(format!("unexpected token {other:?}"))),
+        }
+    }
+}
+
+/// Reads a decimal literal scaled by `decimals`, `1.5` with 18 decimals is 1.5 ether in wei
+fn parse_number(lit: &str, decimals: usize) -> Result<U256, ExprError> {
+    let (int, frac) = lit.split_once('.').unwrap_or((lit, ""));
+    if decimals == 0 && lit.contains('.') {
+        return Err(ExprError::Parse(format!(
+            "{lit} needs a unit, e.g. {lit} ether"
+        )));
+    }
+    if frac.len() > decimals {
+        return Err(ExprError::Parse(format!(
+            "{lit} has more than {decimals} decimals"
+        )));
+    }
+    let digits = format!("{int}{frac:0<decimals$}");
+    U256::from_str_radix(&digits, 10).map_err(|_| ExprError::Parse(format!("invalid number {lit}")))
+}
+
+impl Expr {
+    pub fn parse(src: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser {
+            tokens: tokenize(src)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(ExprError::Parse(format!("unexpected token {token:?}")));
+        }
+        Ok(expr)
+    }
+
+    /// Parses a predicate and checks that it is a boolean over the given variables
+    pub fn predicate(src: &str, types: &HashMap<String, ExprType>) -> Result<Self, ExprError> {
+        let expr = Self::parse(src)?;
+        match expr.check(types)? {
+            ExprType::Bool => Ok(expr),
+            ty => Err(ExprError::Type(format!(
+                "predicate is {ty:?}, expected Bool"
+            ))),
+        }
+    }
+
+    pub fn check(&self, types: &HashMap<String, ExprType>) -> Result<ExprType, ExprError> {
+        match self {
+            Expr::Literal(v) => Ok(v.ty()),
+            Expr::Var(name) => match types.get(name) {
+                Some(ty) => Ok(*ty),
+                None => Err(ExprError::UnknownVariable(name.clone())),
+            },
+            Expr::Unary(UnOp::Not, e) => match e.check(types)? {
+                ExprType::Bool => Ok(ExprType::Bool),
+                ty => Err(ExprError::Type(format!("'!' on {ty:?}"))),
+            },
+            Expr::Unary(UnOp::Neg, e) => match e.check(types)? {
+                ty if ty.is_numeric() => Ok(ExprType::Int),
+                ty => Err(ExprError::Type(format!("'-' on {ty:?}"))),
+            },
+            Expr::Binary(op, lhs, rhs) if op.is_arithmetic() => {
+                match (lhs.check(types)?, rhs.check(types)?) {
+                    (ExprType::Uint, ExprType::Uint) => Ok(ExprType::Uint),
+                    (l, r) if l.is_numeric() && r.is_numeric() => Ok(ExprType::Int),
+                    (l, r) => Err(ExprError::Type(format!("{op:?} on {l:?} and {r:?}"))),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (l, r) = (lhs.check(types)?, rhs.check(types)?);
+                match op {
+                    BinOp::Or | BinOp::And => {
+                        if l != ExprType::Bool || r != ExprType::Bool {
+                            return Err(ExprError::Type(format!("{op:?} on {l:?} and {r:?}")));
+                        }
+                    }
+                    BinOp::Eq | BinOp::Ne => {
+                        if l != r && !(l.is_numeric() && r.is_numeric()) {
+                            return Err(ExprError::Type(format!("comparing {l:?} with {r:?}")));
+                        }
+                    }
+                    _ => {
+                        if !l.is_numeric() || !r.is_numeric() {
+                            return Err(ExprError::Type(format!("ordering {l:?} with {r:?}")));
+                        }
+                    }
+                }
+                Ok(ExprType::Bool)
+            }
+            Expr::Call(func, args) => {
+                let tys = args
+                    .iter()
+                    .map(|a| a.check(types))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let bytes_like = |ty: &ExprType| matches!(ty, ExprType::Bytes | ExprType::String);
+                match func {
+                    Func::Min | Func::Max => match (tys[0], tys[1]) {
+                        (ExprType::Uint, ExprType::Uint) => Ok(ExprType::Uint),
+                        (l, r) if l.is_numeric() && r.is_numeric() => Ok(ExprType::Int),
+                        (l, r) => Err(ExprError::Type(format!("{func:?} of {l:?} and {r:?}"))),
+                    },
+                    Func::Keccak256 if bytes_like(&tys[0]) => Ok(ExprType::Bytes),
+                    Func::EncodePacked => Ok(ExprType::Bytes),
+                    Func::Concat if tys.iter().all(|t| *t == ExprType::String) => {
+                        Ok(ExprType::String)
+                    }
+                    Func::Concat if tys.iter().all(bytes_like) => Ok(ExprType::Bytes),
+                    _ => Err(ExprError::Type(format!("{func:?} of {tys:?}"))),
+                }
+            }
+        }
+    }
+
+    pub fn eval(&self, values: &HashMap<String, ExprValue>) -> Result<ExprValue, ExprError> {
+        match self {
+            Expr::Literal(v) => Ok(v.clone()),
+            Expr::Var(name) => match values.get(name) {
+                Some(v) => Ok(v.clone()),
+                None => Err(ExprError::UnknownVariable(name.clone())),
+            },
+            Expr::Unary(UnOp::Not, e) => match e.eval(values)? {
+                ExprValue::Bool(b) => Ok(ExprValue::Bool(!b)),
+                v => Err(ExprError::Eval(format!("'!' on {:?}", v.ty()))),
+            },
+            Expr::Unary(UnOp::Neg, e) => {
+                let v = e.eval(values)?;
+                match v.as_int().and_then(|i| i.checked_neg()) {
+                    Some(i) => Ok(ExprValue::Int(i, 256)),
+                    None => Err(ExprError::Eval("integer overflow".to_string())),
+                }
+            }
+            Expr::Binary(BinOp::Or, lhs, rhs) => match lhs.eval(values)? {
+                ExprValue::Bool(true) => Ok(ExprValue::Bool(true)),
+                _ => rhs.eval(values),
+            },
+            Expr::Binary(BinOp::And, lhs, rhs) => match lhs.eval(values)? {
+                ExprValue::Bool(false) => Ok(ExprValue::Bool(false)),
+                _ => rhs.eval(values),
+            },
+            Expr::Binary(op, lhs, rhs) if op.is_arithmetic() => {
+                arithmetic(*op, &lhs.eval(values)?, &rhs.eval(values)?)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (l, r) = (lhs.eval(values)?, rhs.eval(values)?);
+                let ord = compare(&l, &r)?;
+                let res = match op {
+                    BinOp::Eq => ord == Some(Ordering::Equal),
+                    BinOp::Ne => ord != Some(Ordering::Equal),
+                    BinOp::Lt => ord == Some(Ordering::Less),
+                    BinOp::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+                    BinOp::Gt => ord == Some(Ordering::Greater),
+                    BinOp::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                    _ => unreachable!(),
+                };
+                Ok(ExprValue::Bool(res))
+            }
+            Expr::Call(func, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.eval(values))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(*func, &args)
+            }
+        }
+    }
+
+    /// Whether the expression reads `var`
+    pub fn uses(&self, var: &str) -> bool {
+        match self {
+            Expr::Literal(_) => false,
+            Expr::Var(name) => name == var,
+            Expr::Unary(_, e) => e.uses(var),
+            Expr::Binary(_, l, r) => l.uses(var) || r.uses(var),
+            Expr::Call(_, args) => args.iter().any(|a| a.uses(var)),
+        }
+    }
+
+    /// Evaluates a checked predicate
+    pub fn matches(&self, values: &HashMap<String, ExprValue>) -> Result<bool, ExprError> {
+        match self.eval(values)? {
+            ExprValue::Bool(b) => Ok(b),
+            v => Err(ExprError::Eval(format!("predicate returned {:?}", v.ty()))),
+        }
+    }
+}
+
+/// Checked integer arithmetic, stays unsigned when both sides are
+fn arithmetic(op: BinOp, l: &ExprValue, r: &ExprValue) -> Result<ExprValue, ExprError> {
+    let overflow = || ExprError::Eval(format!("{op:?} overflows or divides by zero"));
+    if let (ExprValue::Uint(a, _), ExprValue::Uint(b, _)) = (l, r) {
+        let res = match op {
+            BinOp::Add => a.checked_add(*b),
+            BinOp::Sub => a.checked_sub(*b),
+            BinOp::Mul => a.checked_mul(*b),
+            BinOp::Div => a.checked_div(*b),
+            _ => a.checked_rem(*b),
+        };
+        return res.map(|u| ExprValue::Uint(u, 256)).ok_or_else(overflow);
+    }
+    let (a, b) = match (l.as_int(), r.as_int()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Err(overflow()),
+    };
+    let res = match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Div => a.checked_div(b),
+        _ => a.checked_rem(b),
+    };
+    res.map(|i| ExprValue::Int(i, 256)).ok_or_else(overflow)
+}
+
+fn call(func: Func, args: &[ExprValue]) -> Result<ExprValue, ExprError> {
+    let not_bytes = |v: &ExprValue| ExprError::Eval(format!("{func:?} of {:?}", v.ty()));
+    match func {
+        Func::Min | Func::Max => {
+            let ord = compare(&args[0], &args[1])?;
+            let first = match func {
+                Func::Min => ord != Some(Ordering::Greater),
+                _ => ord != Some(Ordering::Less),
+            };
+            Ok(if first { &args[0] } else { &args[1] }.clone())
+        }
+        Func::Keccak256 => match args[0].as_bytes() {
+            Some(bytes) => Ok(ExprValue::Bytes(Bytes::copy_from_slice(
+                keccak256(bytes).as_slice(),
+            ))),
+            None => Err(not_bytes(&args[0])),
+        },
+        Func::EncodePacked => {
+            let mut packed = Vec::new();
+            for arg in args {
+                match arg {
+                    ExprValue::Uint(u, bits) => {
+                        packed.extend_from_slice(&u.to_be_bytes::<32>()[32 - bits / 8..])
+                    }
+                    ExprValue::Int(i, bits) => {
+                        packed.extend_from_slice(&i.to_be_bytes::<32>()[32 - bits / 8..])
+                    }
+                    ExprValue::Bool(b) => packed.push(*b as u8),
+                    ExprValue::Address(a) => packed.extend_from_slice(a.as_slice()),
+                    ExprValue::Bytes(b) => packed.extend_from_slice(b),
+                    ExprValue::String(s) => packed.extend_from_slice(s.as_bytes()),
+                }
+            }
+            Ok(ExprValue::Bytes(Bytes::from(packed)))
+        }
+        Func::Concat => {
+            if let Some(strings) = args
+                .iter()
+                .map(|a| match a {
+                    ExprValue::String(s) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+            {
+                return Ok(ExprValue::String(strings.concat()));
+            }
+            let mut joined = Vec::new();
+            for arg in args {
+                joined.extend_from_slice(arg.as_bytes().ok_or_else(|| not_bytes(arg))?);
+            }
+            Ok(ExprValue::Bytes(Bytes::from(joined)))
+        }
+    }
+}
+
+/// Orders numbers, other values only compare for equality (`None` when different)
+fn compare(l: &ExprValue, r: &ExprValue) -> Result<Option<Ordering>, ExprError> {
+    match (l, r) {
+        (ExprValue::Uint(a, _), ExprValue::Uint(b, _)) => Ok(Some(a.cmp(b))),
+        (ExprValue::Int(a, _), ExprValue::Int(b, _)) => Ok(Some(a.cmp(b))),
+        (ExprValue::Uint(..), ExprValue::Int(b, _)) => match l.as_int() {
+            Some(a) => Ok(Some(a.cmp(b))),
+            None => Ok(Some(Ordering::Greater)),
+        },
+        (ExprValue::Int(a, _), ExprValue::Uint(..)) => match r.as_int() {
+            Some(b) => Ok(Some(a.cmp(&b))),
+            None => Ok(Some(Ordering::Less)),
+        },
+        _ if l.ty() == r.ty() => Ok(if l == r { Some(Ordering::Equal) } else { None }),
+        _ => Err(ExprError::Eval(format!(
+            "comparing {:?} with {:?}",
+            l.ty(),
+            r.ty()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer() -> Event {
+        Event::parse("event Transfer(address indexed from, address indexed to, uint256 value)")
+            .unwrap()
+    }
+
+    fn eval(src: &str) -> ExprValue {
+        Expr::parse(src).unwrap().eval(&HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(eval("1 + 2 * 3"), ExprValue::Uint(U256::from(7), 256));
+        assert_eq!(eval("(1 + 2) * 3"), ExprValue::Uint(U256::from(9), 256));
+        assert_eq!(eval("10 - 4 - 3"), ExprValue::Uint(U256::from(3), 256));
+        assert_eq!(eval("1 + 2 * 3 == 7"), ExprValue::Bool(true));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(eval("true || false && false"), ExprValue::Bool(true));
+        assert_eq!(eval("(true || false) && false"), ExprValue::Bool(false));
+        assert_eq!(eval("!false && 1 < 2"), ExprValue::Bool(true));
+    }
+
+    #[test]
+    fn predicate_is_type_checked_against_the_event() {
+        let event = transfer();
+        let types = event_types(Some(&event));
+        assert!(Expr::predicate("value > 1 ether && from != to", &types).is_ok());
+        assert!(matches!(
+            Expr::predicate("value + 1", &types),
+            Err(ExprError::Type(_))
+        ));
+        assert!(matches!(
+            Expr::predicate("from > 1", &types),
+            Err(ExprError::Type(_))
+        ));
+        assert!(matches!(
+            Expr::predicate("from == value", &types),
+            Err(ExprError::Type(_))
+        ));
+        assert!(matches!(
+            Expr::predicate("amount > 0", &types),
+            Err(ExprError::UnknownVariable(v)) if v == "amount"
+        ));
+    }
+
+    #[test]
+    fn signed_and_unsigned_compare_by_value() {
+        assert_eq!(eval("-1 < 0"), ExprValue::Bool(true));
+        assert_eq!(eval("0 > -1"), ExprValue::Bool(true));
+        assert_eq!(eval("-5 + 5 == 0"), ExprValue::Bool(true));
+        let big = ExprValue::Uint(U256::MAX, 256);
+        let neg = ExprValue::Int(I256::MINUS_ONE, 256);
+        assert_eq!(compare(&big, &neg).unwrap(), Some(Ordering::Greater));
+        assert_eq!(compare(&neg, &big).unwrap(), Some(Ordering::Less));
+    }
+
+    #[test]
+    fn eval_of_missing_variable_fails() {
+        let expr = Expr::parse("value > 0").unwrap();
+        assert!(matches!(
+            expr.eval(&HashMap::new()),
+            Err(ExprError::UnknownVariable(v)) if v == "value"
+        ));
+        let values = HashMap::from([("value".to_string(), ExprValue::Uint(U256::from(1), 256))]);
+        assert!(expr.matches(&values).unwrap());
+    }
+
+    #[test]
+    fn number_literals_take_units() {
+        let ether = U256::from(10).pow(U256::from(18));
+        assert_eq!(
+            eval("1.5 ether"),
+            ExprValue::Uint(ether * U256::from(3) / U256::from(2), 256)
+        );
+        assert_eq!(
+            eval("2 gwei"),
+            ExprValue::Uint(U256::from(2_000_000_000u64), 256)
+        );
+        assert_eq!(eval("7 wei"), ExprValue::Uint(U256::from(7), 256));
+        assert!(matches!(
+            Expr::parse("1.0000000001 gwei"),
+            Err(ExprError::Parse(_))
+        ));
+        assert!(matches!(Expr::parse("1.5"), Err(ExprError::Parse(_))));
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_an_error() {
+        let max = ExprValue::Uint(U256::MAX, 256);
+        let one = ExprValue::Uint(U256::from(1), 256);
+        assert!(arithmetic(BinOp::Add, &max, &one).is_err());
+        assert!(arithmetic(BinOp::Mul, &max, &max).is_err());
+        assert!(Expr::parse("1 - 2").unwrap().eval(&HashMap::new()).is_err());
+        assert!(Expr::parse("1 / 0").unwrap().eval(&HashMap::new()).is_err());
+        assert_eq!(
+            eval("-2 + -1"),
+            ExprValue::Int(I256::try_from(-3).unwrap(), 256)
+        );
+    }
+
+    #[test]
+    fn to_sol_checks_the_input_width() {
+        let v = |n: u64| ExprValue::Uint(U256::from(n), 256);
+        let neg = ExprValue::Int(I256::MINUS_ONE, 256);
+        assert!(v(255).to_sol(&DynSolType::Uint(8)).is_ok());
+        assert!(v(256).to_sol(&DynSolType::Uint(8)).is_err());
+        assert!(neg.to_sol(&DynSolType::Uint(256)).is_err());
+        assert!(v(127).to_sol(&DynSolType::Int(8)).is_ok());
+        assert!(v(128).to_sol(&DynSolType::Int(8)).is_err());
+        assert_eq!(
+            neg.to_sol(&DynSolType::Int(8)).unwrap(),
+            DynSolValue::Int(I256::MINUS_ONE, 8)
+        );
+        let short = ExprValue::Bytes(Bytes::from(vec![1, 2]));
+        assert!(short.to_sol(&DynSolType::FixedBytes(4)).is_ok());
+        assert!(short.to_sol(&DynSolType::FixedBytes(1)).is_err());
+    }
+
+    #[test]
+    fn encode_packed_uses_declared_widths() {
+        let expr = Expr::parse("abi.encodePacked(small, neg, flag, 1)").unwrap();
+        let values = HashMap::from([
+            (
+                "small".to_string(),
+                ExprValue::from_sol(&DynSolValue::Uint(U256::from(0x0102), 16)).unwrap(),
+            ),
+            (
+                "neg".to_string(),
+                ExprValue::from_sol(&DynSolValue::Int(I256::MINUS_ONE, 8)).unwrap(),
+            ),
+            ("flag".to_string(), ExprValue::Bool(true)),
+        ]);
+        let mut expected = vec![0x01, 0x02, 0xff, 0x01];
+        expected.extend_from_slice(&U256::from(1).to_be_bytes::<32>());
+        assert_eq!(
+            expr.eval(&values).unwrap(),
+            ExprValue::Bytes(Bytes::from(expected))
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use alloy::primitives::Address;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Records kept per user, the oldest are dropped first
+const MAX_RECORDS_PER_USER: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryKind {
+    Sink,
+    Action,
+    /// blocks of a `from_block` backfill that couldn't be read
+    Backfill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryState {
+    Pending,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+/// One delivery to a sink or one relayer action, with its latest attempt
+#[derive(Clone, Debug)]
+pub struct DeliveryRecord {
+    pub id: String,
+    pub user: Address,
+    pub sub_id: String,
+    pub event_id: String,
+    pub kind: DeliveryKind,
+    pub destination: String,
+    pub attempts: u32,
+    pub state: DeliveryState,
+    pub http_status: Option<u16>,
+    pub tx_hash: Option<String>,
+    /// position of the action in the subscription's plan
+    pub step: Option<usize>,
+    pub error: String,
+    pub latency_ms: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryFilter {
+    pub sub_id: Option<String>,
+    pub state: Option<DeliveryState>,
+    /// unix millis, inclusive
+    pub from: Option<u64>,
+    /// unix millis, exclusive
+    pub to: Option<u64>,
+    pub limit: usize,
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+pub struct DeliveryHistory {
+    records: DashMap<String, DeliveryRecord>,
+    by_user: DashMap<Address, VecDeque<String>>,
+}
+
+impl DeliveryHistory {
+    /// Opens a pending record and returns its id
+    pub fn start(
+        &self,
+        user: Address,
+        sub_id: &str,
+        event_id: &str,
+        kind: DeliveryKind,
+        destination: String,
+    ) -> String {
+        let now = now_ms();
+        let id = format!("{:016x}{:08x}", now, rand::random::<u32>());
+        let record = DeliveryRecord {
+            id: id.clone(),
+            user,
+            sub_id: sub_id.to_string(),
+            event_id: event_id.to_string(),
+            kind,
+            destination,
+            attempts: 0,
+            state: DeliveryState::Pending,
+            http_status: None,
+            tx_hash: None,
+            step: None,
+            error: String::new(),
+            latency_ms: 0,
+            created_at: now,
+            updated_at: now,
+        };
+        self.records.insert(id.clone(), record);
+
+        let mut ids = self.by_user.entry(user).or_default();
+        ids.push_back(id.clone());
+        while ids.len() > MAX_RECORDS_PER_USER {
+            if let Some(old) = ids.pop_front() {
+                self.records.remove(&old);
+            }
+        }
+        id
+    }
+
+    /// Applies `f` to a record and refreshes its latency
+    pub fn update<F: FnOnce(&mut DeliveryRecord)>(&self, id: &str, f: F) {
+        if let Some(mut record) = self.records.get_mut(id) {
+            f(&mut record);
+            record.updated_at = now_ms();
+            record.latency_ms = record.updated_at.saturating_sub(record.created_at);
+        }
+    }
+
+    pub fn get(&self, user: Address, id: &str) -> Option<DeliveryRecord> {
+        match self.records.get(id) {
+            Some(record) if record.user == user => Some(record.clone()),
+            _ => None,
+        }
+    }
+
+    /// Newest first
+    pub fn list(&self, user: Address, filter: &DeliveryFilter) -> Vec<DeliveryRecord> {
+        let ids = match self.by_user.get(&user) {
+            Some(ids) => ids.clone(),
+            None => return Vec::new(),
+        };
+        let mut found = Vec::new();
+        for id in ids.iter().rev() {
+            let record = match self.records.get(id) {
+                Some(record) => record.clone(),
+                None => continue,
+            };
+            if filter.sub_id.as_ref().is_some_and(|s| *s != record.sub_id)
+                || filter.state.is_some_and(|s| s != record.state)
+                || filter.from.is_some_and(|from| record.created_at < from)
+                || filter.to.is_some_and(|to| record.created_at >= to)
+            {
+                continue;
+            }
+            found.push(record);
+            if filter.limit > 0 && found.len() >= filter.limit {
+                break;
+            }
+        }
+        found
+    }
+}
@@ -1,6 +1,7 @@
 use alloy::{
-    network::{Ethereum, EthereumWallet},
-    primitives::Address,
+    network::{Ethereum, EthereumWallet, TransactionBuilder},
+    primitives::{Address, B256, FixedBytes, keccak256},
+    providers::ext::DebugApi,
     providers::{
         DynProvider, Identity, Provider, ProviderBuilder, RootProvider, WalletProvider, WsConnect,
         fillers::{
@@ -9,10 +10,14 @@ use alloy::{
         },
     },
     pubsub::{Subscription, SubscriptionStream},
-    rpc::types::{EIP1186StorageProof, Filter, Log},
+    rpc::types::{
+        EIP1186StorageProof, Filter, Header, Log,
+        trace::geth::{CallConfig, CallFrame, GethDebugTracingOptions, TraceResult},
+    },
 };
 
-use futures::StreamExt;
+use alloy::consensus::Transaction as _;
+use futures::{StreamExt, stream};
 use std::{cell::RefCell, default, error::Error, sync::Arc};
 
 use tokio::sync::{
@@ -29,10 +34,21 @@ use rpc_types::*;
 pub mod relayer;
 use crate::{
     chainhooks::UserRegistrationResponse,
-    rpchandler::{relayer::UserUpdates, transactionTypes::RawTransaction},
+    rpchandler::{
+        relayer::UserUpdates,
+        transactionTypes::{RawTransaction, RelayerError},
+    },
 };
 use std::collections::BTreeMap;
+pub mod batch;
+pub mod eventTypes;
+pub mod expr;
+pub mod history;
+pub mod scheduler;
+pub mod sinks;
+pub mod template;
 pub mod transactionTypes;
+use eventTypes::{BlockInfo, DecodedLog, NativeTransfer};
 
 type providerType = FillProvider<
     JoinFill<
@@ -48,20 +64,60 @@ type providerType = FillProvider<
     RootProvider,
 >;
 
+const MAX_ADDRESSES_PER_SUBSCRIPTION: usize = 100;
+const MAX_WATCHED_ADDRESSES_PER_USER: usize = 500;
+const MAX_ANY_ADDRESS_SUBSCRIPTIONS: usize = 2;
+/// Stream map key prefix of `from_block` backfills, followed by the subscription id
+const BACKFILL_PREFIX: &str = "backfill|";
+const BACKFILL_CHUNK: u64 = 2_000;
+const MAX_BACKFILL_CHUNK: u64 = 50_000;
+/// Pages grow while they return fewer logs than this and shrink above twice as many
+const BACKFILL_TARGET_LOGS: usize = 1_000;
+const BACKFILL_RETRIES: u32 = 5;
+/// Wait before retrying a failed backfill call, multiplied by the number of failures so far
+const BACKFILL_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Transfers subscription reading a shared block
+struct TransferWatch {
+    subid: String,
+    user: Address,
+    addresses: Vec<Address>,
+    internal: bool,
+}
+
+/// Node subscription shared by every ChainHooks subscription with the same filter
+#[derive(Clone, Debug)]
+struct Upstream {
+    id: B256,
+    subs: Vec<String>,
+}
+
 #[derive(Clone)]
 pub struct chainRpc {
     chainid: usize,
     subscriptions: DashMap<Address, Vec<(String, SubscriptionType)>>,
     active_subscriptions: DashMap<String, SubscriptionType>,
+    /// keyed by `upstream_key`, which also keys the stream map
+    upstreams: DashMap<String, Upstream>,
+    sub_upstream: DashMap<String, String>,
+    /// live logs held back while their subscription backfills
+    backfills: DashMap<String, Vec<Log>>,
+    /// header of the last block read for a log trigger, logs arrive grouped by block
+    last_block: Arc<Mutex<Option<BlockInfo>>>,
     event_sender: mpsc::Sender<RpcTypes>,
     provider: Arc<Mutex<providerType>>,
+    /// next nonce of each relayer on this chain, read from the node when unset.
+    /// The lock is held from nonce assignment until the node accepted the transaction
+    nonces: Arc<DashMap<Address, Arc<Mutex<Option<u64>>>>>,
+    /// blocks waiting for their transfer scan, one task drains them so deliveries keep block order
+    transfer_queue: mpsc::UnboundedSender<(Header, Vec<TransferWatch>)>,
 }
 
 impl chainRpc {
     async fn new(
         chainid: usize,
         URL: String,
-        subscription: SubscriptionType,
+        subscription: Option<SubscriptionType>,
         mut command_receiver: mpsc::Receiver<(SubscriptionType, oneshot::Sender<RpcTypes>)>,
         log_sender: mpsc::Sender<RpcTypes>,
     ) -> Result<(), Box<dyn Error>> {
@@ -76,35 +132,33 @@ impl chainRpc {
             .connect_ws(ws)
             .await?;
 
-        let (user, filter) = Self::getFilter(&subscription).unwrap();
-        let sub = match provider.subscribe_logs(&filter).await {
-            Ok(sub) => sub,
-            Err(e) => return Err(Box::new(RpcTypeError::SubscriptionError)),
-        };
-
-        let key = sub.local_id().clone().to_string();
-
-        let mut chainrpc = chainRpc {
+        let (transfer_queue, transfer_rx) = mpsc::unbounded_channel();
+        let chainrpc = chainRpc {
             chainid: chainid,
             subscriptions: Default::default(),
             active_subscriptions: Default::default(),
+            upstreams: Default::default(),
+            sub_upstream: Default::default(),
+            backfills: Default::default(),
+            last_block: Default::default(),
             event_sender: log_sender,
             provider: Arc::new(Mutex::new(provider)),
+            nonces: Default::default(),
+            transfer_queue,
         };
+        tokio::spawn(transfer_scanner(
+            chainrpc.provider.clone(),
+            chainrpc.event_sender.clone(),
+            transfer_rx,
+        ));
 
-        chainrpc
-            .active_subscriptions
-            .insert(key.clone(), subscription.clone());
-
-        chainrpc
-            .subscriptions
-            .insert(user, vec![(key.clone(), subscription.clone())]);
+        let mut stream_map = StreamMap::new();
+        if let Some(subscription) = subscription {
+            chainrpc.subscribe(subscription, &mut stream_map).await?;
+        }
 
         let chain_rpc = Arc::new(chainrpc);
         let rpc_clone = chain_rpc.clone();
-        let mut stream_map = StreamMap::new();
-
-        stream_map.insert(key, sub.into_stream());
 
         tokio::task::spawn(async move {
             loop {
@@ -119,9 +173,9 @@ impl chainRpc {
                             eprint!("cmd error :{e}");
                         }
                     }
-                    Some((subid,event)) = stream_map.next() => {
+                    Some((key,event)) = stream_map.next() => {
                         let mut rpc_locked = rpc_clone.clone();
-                        if let Err(e) = rpc_locked.handleevent(event,subid).await{
+                        if let Err(e) = rpc_locked.handleevent(event,key).await{
                             eprint!("cmd error :{e}");
                         }
                     }
@@ -136,101 +190,371 @@ impl chainRpc {
             SubscriptionType::Subscription {
                 user,
                 chainid,
-                address,
+                addresses,
                 event_signature,
+                topics,
+                ..
             } => {
-                let filter = Filter::new()
-                    .address(address.clone())
-                    .event(event_signature);
+                let mut filter = Filter::new();
+                if !event_signature.is_empty() {
+                    filter = filter.event_signature(keccak256(event_signature.as_bytes()));
+                }
+                // no addresses means the event is matched on any contract
+                if !addresses.is_empty() {
+                    filter = filter.address(addresses.clone());
+                }
+                if !topics[0].is_empty() {
+                    filter = filter.topic1(topics[0].clone());
+                }
+                if !topics[1].is_empty() {
+                    filter = filter.topic2(topics[1].clone());
+                }
+                if !topics[2].is_empty() {
+                    filter = filter.topic3(topics[2].clone());
+                }
                 Some((user.clone(), filter))
             }
             _ => None,
         }
     }
 
+    /// Identifies the node subscription `cmd` needs, subscriptions with equal keys share one
+    fn upstream_key(cmd: &SubscriptionType) -> Option<String> {
+        match cmd {
+            SubscriptionType::Subscription {
+                addresses,
+                event_signature,
+                topics,
+                ..
+            } => {
+                let mut addresses = addresses.clone();
+                addresses.sort();
+                let topics = topics.clone().map(|mut t| {
+                    t.sort();
+                    t
+                });
+                Some(format!("logs|{event_signature}|{addresses:?}|{topics:?}"))
+            }
+            SubscriptionType::NewHeads { .. } | SubscriptionType::Transfers { .. } => {
+                Some("newHeads".to_string())
+            }
+            SubscriptionType::PendingTx { .. } => Some("newPendingTransactions".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Opens the node subscription behind `cmd`
+    async fn open_stream(
+        &self,
+        cmd: &SubscriptionType,
+    ) -> Result<(B256, ChainStream), RpcTypeError> {
+        let provider = self.provider.lock().await;
+        match cmd {
+            SubscriptionType::Subscription { .. } => {
+                let (_, filter) = match Self::getFilter(cmd) {
+                    Some(filter) => filter,
+                    None => return Err(RpcTypeError::SubscriptionError),
+                };
+                match provider.subscribe_logs(&filter).await {
+                    Ok(sub) => Ok((
+                        *sub.local_id(),
+                        sub.into_stream().map(ChainEvent::Log).boxed(),
+                    )),
+                    Err(_) => Err(RpcTypeError::SubscriptionError),
+                }
+            }
+            // transfers are read from the full block behind each header
+            SubscriptionType::NewHeads { .. } | SubscriptionType::Transfers { .. } => {
+                match provider.subscribe_blocks().await {
+                    Ok(sub) => Ok((
+                        *sub.local_id(),
+                        sub.into_stream().map(ChainEvent::Block).boxed(),
+                    )),
+                    Err(_) => Err(RpcTypeError::SubscriptionError),
+                }
+            }
+            // needs a node that serves full pending transaction bodies
+            SubscriptionType::PendingTx { .. } => {
+                match provider.subscribe_full_pending_transactions().await {
+                    Ok(sub) => Ok((
+                        *sub.local_id(),
+                        sub.into_stream().map(ChainEvent::PendingTx).boxed(),
+                    )),
+                    Err(_) => Err(RpcTypeError::SubscriptionError),
+                }
+            }
+            _ => Err(RpcTypeError::SubscriptionError),
+        }
+    }
+
+    /// Registers `cmd` under a new subscription id, joining the upstream of an identical filter
+    /// or opening a new one
+    async fn subscribe(
+        &self,
+        cmd: SubscriptionType,
+        stream_map: &mut StreamMap<String, ChainStream>,
+    ) -> Result<String, RpcTypeError> {
+        let key = match Self::upstream_key(&cmd) {
+            Some(key) => key,
+            None => return Err(RpcTypeError::SubscriptionError),
+        };
+        let subid = format!("0x{:032x}", rand::random::<u128>());
+        let shared = match self.upstreams.get_mut(&key) {
+            Some(mut upstream) => {
+                upstream.subs.push(subid.clone());
+                true
+            }
+            None => false,
+        };
+        if !shared {
+            let (id, stream) = self.open_stream(&cmd).await?;
+            self.upstreams.insert(
+                key.clone(),
+                Upstream {
+                    id,
+                    subs: vec![subid.clone()],
+                },
+            );
+            stream_map.insert(key.clone(), stream);
+        }
+        self.sub_upstream.insert(subid.clone(), key);
+        if let SubscriptionType::Subscription {
+            from_block: Some(from),
+            ..
+        } = &cmd
+        {
+            if let Some((_, filter)) = Self::getFilter(&cmd) {
+                // the live stream is already open, so the backfill head it reads leaves no gap
+                let provider = self.provider.lock().await.clone();
+                self.backfills.insert(subid.clone(), Vec::new());
+                stream_map.insert(
+                    format!("{BACKFILL_PREFIX}{subid}"),
+                    backfill_stream(provider, filter, *from),
+                );
+            }
+        }
+        self.add_subscription(subid.clone(), cmd);
+        Ok(subid)
+    }
+
+    /// Drops a subscription, the node subscription is cancelled with its last subscriber
+    async fn unsubscribe(&self, subid: &str, stream_map: &mut StreamMap<String, ChainStream>) {
+        if self.backfills.remove(subid).is_some() {
+            stream_map.remove(&format!("{BACKFILL_PREFIX}{subid}"));
+        }
+        let key = match self.sub_upstream.remove(subid) {
+            Some((_, key)) => key,
+            None => return,
+        };
+        let unused = match self.upstreams.get_mut(&key) {
+            Some(mut upstream) => {
+                upstream.subs.retain(|s| s != subid);
+                upstream.subs.is_empty()
+            }
+            None => false,
+        };
+        if unused {
+            stream_map.remove(&key);
+            if let Some((_, upstream)) = self.upstreams.remove(&key) {
+                if let Err(e) = self.provider.lock().await.unsubscribe(upstream.id).await {
+                    eprintln!("Error cancelling node subscription {}: {e}", upstream.id);
+                }
+            }
+        }
+    }
+
+    fn add_subscription(&self, subid: String, cmd: SubscriptionType) {
+        let user = match &cmd {
+            SubscriptionType::Subscription { user, .. }
+            | SubscriptionType::NewHeads { user, .. }
+            | SubscriptionType::PendingTx { user, .. }
+            | SubscriptionType::Transfers { user, .. } => *user,
+            _ => return,
+        };
+        self.active_subscriptions.insert(subid.clone(), cmd.clone());
+        self.subscriptions
+            .entry(user)
+            .or_default()
+            .push((subid, cmd));
+    }
+
+    /// Per user limits on this chain, checked before a new subscription is opened
+    fn check_quota(&self, user: &Address, addresses: &[Address]) -> Result<(), RpcTypeError> {
+        if addresses.len() > MAX_ADDRESSES_PER_SUBSCRIPTION {
+            return Err(RpcTypeError::QuotaExceeded(format!(
+                "at most {MAX_ADDRESSES_PER_SUBSCRIPTION} addresses per subscription"
+            )));
+        }
+        let mut watched = addresses.len();
+        let mut any_address = addresses.is_empty() as usize;
+        if let Some(subs) = self.subscriptions.get(user) {
+            for (_, sub) in subs.iter() {
+                match sub {
+                    SubscriptionType::Subscription { addresses, .. } => {
+                        watched += addresses.len();
+                        any_address += addresses.is_empty() as usize;
+                    }
+                    SubscriptionType::Transfers { addresses, .. } => watched += addresses.len(),
+                    _ => {}
+                }
+            }
+        }
+        if watched > MAX_WATCHED_ADDRESSES_PER_USER {
+            return Err(RpcTypeError::QuotaExceeded(format!(
+                "at most {MAX_WATCHED_ADDRESSES_PER_USER} watched addresses per chain"
+            )));
+        }
+        if any_address > MAX_ANY_ADDRESS_SUBSCRIPTIONS {
+            return Err(RpcTypeError::QuotaExceeded(format!(
+                "at most {MAX_ANY_ADDRESS_SUBSCRIPTIONS} any-address subscriptions per chain"
+            )));
+        }
+        Ok(())
+    }
+
     async fn handlecmd(
         &self,
         cmd: SubscriptionType,
         res_receiver: oneshot::Sender<RpcTypes>,
-        stream_map: &mut StreamMap<String, SubscriptionStream<Log>>,
+        stream_map: &mut StreamMap<String, ChainStream>,
     ) -> Result<(), Box<dyn Error>> {
         match cmd.clone() {
-            SubscriptionType::Subscription {
-                user,
-                chainid,
-                address,
-                event_signature,
-            } => {
-                let (user, filter) = Self::getFilter(&cmd).unwrap();
+            SubscriptionType::Subscription { .. }
+            | SubscriptionType::NewHeads { .. }
+            | SubscriptionType::PendingTx { .. }
+            | SubscriptionType::Transfers { .. } => {
+                if let SubscriptionType::Subscription {
+                    user, addresses, ..
+                }
+                | SubscriptionType::Transfers {
+                    user, addresses, ..
+                } = &cmd
+                {
+                    if let Err(e) = self.check_quota(user, addresses) {
+                        res_receiver.send(RpcTypes::Response {
+                            success: false,
+                            message: e.to_string(),
+                        });
+                        return Err(Box::new(e));
+                    }
+                }
 
-                let sub = match self.provider.lock().await.subscribe_logs(&filter).await {
-                    Ok(sub) => sub,
+                let subid = match self.subscribe(cmd.clone(), stream_map).await {
+                    Ok(subid) => subid,
                     Err(e) => {
                         let res = RpcTypes::Response {
                             success: false,
                             message: "error while subscription".to_string(),
                         };
                         res_receiver.send(res);
-                        return Err(Box::new(RpcTypeError::SubscriptionError));
+                        return Err(Box::new(e));
                     }
                 };
 
-                let subid = sub.local_id().to_string();
-
-                self.active_subscriptions.insert(subid.clone(), cmd.clone());
-
-                if let Some(mut subs) = self.subscriptions.get_mut(&user) {
-                    subs.push((subid.clone(), cmd.clone()));
-                } else {
-                    self.subscriptions
-                        .insert(user.clone(), vec![(subid.clone(), cmd.clone())]);
-                }
-
                 let res = RpcTypes::Response {
                     success: true,
-                    message: subid.clone(),
+                    message: subid,
                 };
                 res_receiver.send(res);
-                stream_map.insert(subid, sub.into_stream());
             }
 
             SubscriptionType::Transaction {
                 user,
                 signer,
-                tx,
+                action,
+                log,
                 db,
+                sub_id,
+                trigger,
+                delivery_id,
             } => {
                 let mut provider = self.provider.lock().await;
+                let from = signer.address();
                 let wallet = provider.wallet_mut();
-                if let None = wallet.signer_by_address(signer.address()) {
+                if let None = wallet.signer_by_address(from) {
                     wallet.register_signer(signer);
                 }
                 let provider_dup = provider.clone();
+                let event_sender = self.event_sender.clone();
+                let nonce = self.nonces.entry(from).or_default().clone();
                 // let res = provider.send_transaction(tx);
                 tokio::spawn(async move {
-                    if let Ok(tx_reciept) = provider_dup.send_transaction(tx).await {
-                        if let Ok(receipt) = tx_reciept.get_receipt().await {
-                            let tx_hash = receipt.transaction_hash;
-                            let str = match serde_json::to_string(&receipt) {
-                                Ok(t) => t,
-                                Err(e) => {
-                                    return Err(e);
+                    let mut result = RelayerTxResult::default();
+                    let tx = async {
+                        let tx = action
+                            .prepare(&provider_dup, from, &log, &trigger)
+                            .await?
+                            .with_from(from)
+                            .with_chain_id(action.chain_id as u64);
+                        action.gas.check_base_fee(&provider_dup).await?;
+                        // a transaction that would revert isn't broadcast
+                        let estimate = action.simulate(&provider_dup, &tx).await?;
+                        action.gas.apply(&provider_dup, tx, estimate).await
+                    };
+                    let tx = match tx.await {
+                        Ok(tx) => Some(tx),
+                        Err(e) => {
+                            let reason = e.downcast_ref::<RelayerError>();
+                            result.skipped = matches!(reason, Some(RelayerError::GuardNotMet(_)));
+                            result.deferred =
+                                matches!(reason, Some(RelayerError::BaseFeeTooHigh(..)));
+                            result.error = e.to_string();
+                            None
+                        }
+                    };
+                    if let Some(tx) = tx {
+                        // concurrent actions of one relayer must not reuse a nonce
+                        let mut next = nonce.lock().await;
+                        let sent = match *next {
+                            Some(n) => Ok(n),
+                            None => provider_dup.get_transaction_count(from).pending().await,
+                        };
+                        let sent = match sent {
+                            Ok(n) => {
+                                let res = provider_dup.send_transaction(tx.with_nonce(n)).await;
+                                // a rejected transaction may leave the cached nonce off, re-read it next time
+                                *next = res.is_ok().then_some(n + 1);
+                                res
+                            }
+                            Err(e) => Err(e),
+                        };
+                        drop(next);
+                        match sent {
+                            Ok(tx_reciept) => {
+                                result.hash = tx_reciept.tx_hash().to_string();
+                                match tx_reciept.get_receipt().await {
+                                    Ok(receipt) => {
+                                        let tx_hash = receipt.transaction_hash;
+                                        result.success = receipt.status();
+                                        if !result.success {
+                                            result.error = "transaction reverted".to_string();
+                                        }
+                                        if let Ok(str) = serde_json::to_string(&receipt) {
+                                            if let Some(mut t) = db.get_mut(&user) {
+                                                let update = UserUpdates {
+                                                    Message: str,
+                                                    tx: tx_hash.to_string(),
+                                                };
+                                                t.push(update);
+                                            }
+                                        }
+                                    }
+                                    Err(e) => result.error = e.to_string(),
                                 }
-                            };
-                            if let Some(mut t) = db.get_mut(&user) {
-                                let update = UserUpdates {
-                                    Message: str.clone(),
-                                    tx: tx_hash.to_string(),
-                                };
-                                let update = UserUpdates {
-                                    Message: str,
-                                    tx: tx_hash.to_string(),
-                                };
-                                t.push(update);
                             }
+                            Err(e) => result.error = e.to_string(),
                         }
                     }
-                    Ok(())
+                    let res = RpcTypes::TxResult {
+                        user,
+                        sub_id,
+                        trigger,
+                        delivery_id,
+                        result,
+                    };
+                    if let Err(e) = event_sender.send(res).await {
+                        eprintln!("Error reporting transaction result: {e}");
+                    }
                 });
             }
 
@@ -239,7 +563,7 @@ impl chainRpc {
                 if let Some(mut user_subs) = self.subscriptions.get_mut(&user) {
                     user_subs.retain(|(s, _)| *s != subs);
                 }
-                stream_map.remove(&subs);
+                self.unsubscribe(&subs, stream_map).await;
                 res_receiver.send(RpcTypes::Response {
                     success: true,
                     message: "removed the subscription".to_string(),
@@ -250,31 +574,469 @@ impl chainRpc {
         Ok(())
     }
 
-    async fn handleevent(&self, event: Log, subid: String) -> Result<(), Box<dyn Error>> {
-        let subscription = self.active_subscriptions.get(&subid);
-        match subscription {
-            Some(sub) => match sub.clone() {
-                SubscriptionType::Subscription {
+    /// Fans an upstream item out to every subscription sharing it
+    async fn handleevent(&self, event: ChainEvent, key: String) -> Result<(), Box<dyn Error>> {
+        if let Some(subid) = key.strip_prefix(BACKFILL_PREFIX) {
+            return self.handle_backfill(subid.to_string(), event).await;
+        }
+        let subs = match self.upstreams.get(&key) {
+            Some(upstream) => upstream.subs.clone(),
+            None => return Err(Box::new(RpcTypeError::NoSubscriptionFound)),
+        };
+        let mut watchers = Vec::new();
+        for subid in subs {
+            match self.active_subscriptions.get(&subid).map(|s| s.clone()) {
+                Some(SubscriptionType::Transfers {
                     user,
-                    chainid,
-                    address,
-                    event_signature,
-                } => {
-                    let rpcevent = RpcTypes::UserLog {
-                        user: user.clone(),
-                        sub_id: subid,
-                        log: event,
-                    };
-                    self.event_sender.send(rpcevent).await;
+                    addresses,
+                    internal,
+                    ..
+                }) => watchers.push(TransferWatch {
+                    subid,
+                    user,
+                    addresses,
+                    internal,
+                }),
+                Some(sub) => {
+                    if let ChainEvent::Log(log) = &event {
+                        if let Some(mut held) = self.backfills.get_mut(&subid) {
+                            held.push(log.clone());
+                            continue;
+                        }
+                    }
+                    if let Err(e) = self
+                        .dispatch(sub, subid.clone(), event.clone(), false)
+                        .await
+                    {
+                        eprintln!("Error handling event for {subid}: {e}");
+                    }
                 }
-                _ => return Ok(()),
-            },
+                None => {}
+            }
+        }
+        if let ChainEvent::Block(header) = event {
+            if !watchers.is_empty() {
+                self.dispatch_transfers(header, watchers).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a block to be read once for every transfer watcher, the block body and traces are fetched off the event loop
+    async fn dispatch_transfers(&self, header: Header, watchers: Vec<TransferWatch>) {
+        if self.transfer_queue.send((header, watchers)).is_err() {
+            eprintln!("Transfer scanner of chain {} stopped", self.chainid);
+        }
+    }
+
+    /// Delivers the logs of a backfill, then the live logs held back meanwhile that are past its last block
+    async fn handle_backfill(
+        &self,
+        subid: String,
+        event: ChainEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        let subscription = match self.active_subscriptions.get(&subid) {
+            Some(sub) => sub.clone(),
             None => return Err(Box::new(RpcTypeError::NoSubscriptionFound)),
+        };
+        match event {
+            ChainEvent::Backfilled(last) => {
+                let held = match self.backfills.remove(&subid) {
+                    Some((_, held)) => held,
+                    None => return Ok(()),
+                };
+                for log in held
+                    .into_iter()
+                    .filter(|l| l.block_number.is_none_or(|n| n > last))
+                {
+                    self.dispatch(
+                        subscription.clone(),
+                        subid.clone(),
+                        ChainEvent::Log(log),
+                        false,
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            ChainEvent::BackfillGap { from, to, error } => {
+                let user = match subscription {
+                    SubscriptionType::Subscription { user, .. } => user,
+                    _ => return Ok(()),
+                };
+                let gap = RpcTypes::BackfillGap {
+                    user,
+                    sub_id: subid,
+                    from,
+                    to,
+                    error,
+                };
+                self.event_sender.send(gap).await?;
+                Ok(())
+            }
+            event => self.dispatch(subscription, subid, event, true).await,
+        }
+    }
+
+    /// Header fields of the block `log` was emitted in
+    async fn block_info(&self, log: &Log) -> Option<BlockInfo> {
+        let hash = log.block_hash?;
+        let mut last_block = self.last_block.lock().await;
+        if let Some(block) = last_block.as_ref().filter(|b| b.hash == hash) {
+            return Some(block.clone());
+        }
+        let provider = self.provider.lock().await.clone();
+        let block = match provider.get_block_by_hash(hash).await {
+            Ok(Some(block)) => BlockInfo::from_header(&block.header),
+            Ok(None) => return None,
+            Err(e) => {
+                eprintln!("Error reading block {hash}: {e}");
+                return None;
+            }
+        };
+        *last_block = Some(block.clone());
+        Some(block)
+    }
+
+    async fn dispatch(
+        &self,
+        subscription: SubscriptionType,
+        subid: String,
+        event: ChainEvent,
+        historical: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        match (subscription, event) {
+            (
+                SubscriptionType::Subscription {
+                    user,
+                    event: event_abi,
+                    filter,
+                    needs_block,
+                    ..
+                },
+                ChainEvent::Log(event),
+            ) => {
+                let mut decoded = DecodedLog::from_log(subid.clone(), &event, event_abi.as_ref());
+                if let Some(e) = &decoded.decode_error {
+                    eprintln!("Log of {subid} delivered undecoded: {e}");
+                }
+                decoded.historical = historical;
+                if let Some(filter) = filter {
+                    match filter.matches(&expr::event_values(&decoded)) {
+                        Ok(true) => {}
+                        Ok(false) => return Ok(()),
+                        Err(e) => {
+                            eprintln!("Error evaluating filter of {subid}: {e}");
+                            return Ok(());
+                        }
+                    }
+                }
+                if needs_block {
+                    decoded.block = self.block_info(&event).await;
+                }
+                let rpcevent = RpcTypes::UserLog {
+                    user: user.clone(),
+                    sub_id: subid,
+                    log: event,
+                    decoded,
+                };
+                self.event_sender.send(rpcevent).await;
+            }
+            (SubscriptionType::NewHeads { user, every, .. }, ChainEvent::Block(header)) => {
+                if every > 1 && header.number % every != 0 {
+                    return Ok(());
+                }
+                let log = Log {
+                    block_number: Some(header.number),
+                    block_hash: Some(header.hash),
+                    block_timestamp: Some(header.timestamp),
+                    ..Default::default()
+                };
+                let rpcevent = RpcTypes::UserLog {
+                    user,
+                    decoded: DecodedLog::from_header(subid.clone(), &header),
+                    sub_id: subid,
+                    log,
+                };
+                self.event_sender.send(rpcevent).await;
+            }
+            (
+                SubscriptionType::PendingTx {
+                    user,
+                    to,
+                    selectors,
+                    abi,
+                    ..
+                },
+                ChainEvent::PendingTx(tx),
+            ) => {
+                if !to.is_empty() && !tx.to().is_some_and(|addr| to.contains(&addr)) {
+                    return Ok(());
+                }
+                let selector = tx.input().get(..4).map(FixedBytes::<4>::from_slice);
+                if !selectors.is_empty() && !selector.is_some_and(|s| selectors.contains(&s)) {
+                    return Ok(());
+                }
+                let decoded = DecodedLog::from_pending_tx(subid.clone(), &tx, abi.as_ref());
+                let log = Log {
+                    transaction_hash: decoded.tx_hash,
+                    ..Default::default()
+                };
+                let rpcevent = RpcTypes::UserLog {
+                    user,
+                    sub_id: subid,
+                    log,
+                    decoded,
+                };
+                self.event_sender.send(rpcevent).await;
+            }
+            _ => {}
         }
         Ok(())
     }
 }
 
+/// Paging state of a `from_block` backfill
+struct Backfill {
+    provider: providerType,
+    filter: Filter,
+    next: u64,
+    head: Option<u64>,
+    chunk: u64,
+    failures: u32,
+}
+
+/// Pages `eth_getLogs` from `from` up to the head at the time it starts, then yields `ChainEvent::Backfilled`.
+/// Pages halve when the node rejects a range or returns many logs, and double while they stay small.
+/// Failed calls are retried with a growing delay, blocks still unread after that are yielded as a `BackfillGap`
+fn backfill_stream(provider: providerType, filter: Filter, from: u64) -> ChainStream {
+    let start = Backfill {
+        provider,
+        filter,
+        next: from,
+        head: None,
+        chunk: BACKFILL_CHUNK,
+        failures: 0,
+    };
+    stream::unfold(Some(start), move |state| async move {
+        let mut state = state?;
+        let head = match state.head {
+            Some(head) => head,
+            None => match state.provider.get_block_number().await {
+                Ok(head) => {
+                    state.failures = 0;
+                    *state.head.insert(head)
+                }
+                Err(e) => {
+                    state.failures += 1;
+                    if state.failures >= BACKFILL_RETRIES {
+                        let gap = ChainEvent::BackfillGap {
+                            from,
+                            to: None,
+                            error: format!("can't read the chain head: {e}"),
+                        };
+                        let done = ChainEvent::Backfilled(from.saturating_sub(1));
+                        return Some((vec![gap, done], None));
+                    }
+                    tokio::time::sleep(BACKFILL_RETRY_DELAY * state.failures).await;
+                    return Some((Vec::new(), Some(state)));
+                }
+            },
+        };
+        loop {
+            if state.next > head {
+                return Some((vec![ChainEvent::Backfilled(head)], None));
+            }
+            let to = head.min(state.next + state.chunk - 1);
+            let page = state.filter.clone().from_block(state.next).to_block(to);
+            match state.provider.get_logs(&page).await {
+                Ok(logs) => {
+                    state.next = to + 1;
+                    state.failures = 0;
+                    if logs.len() < BACKFILL_TARGET_LOGS {
+                        state.chunk = (state.chunk * 2).min(MAX_BACKFILL_CHUNK);
+                    } else if logs.len() > BACKFILL_TARGET_LOGS * 2 {
+                        state.chunk = (state.chunk / 2).max(1);
+                    }
+                    if !logs.is_empty() {
+                        let events = logs.into_iter().map(ChainEvent::Log).collect();
+                        return Some((events, Some(state)));
+                    }
+                }
+                // most nodes cap the range or the result size of a single call
+                Err(_) if state.chunk > 1 => state.chunk /= 2,
+                Err(e) => {
+                    state.failures += 1;
+                    if state.failures >= BACKFILL_RETRIES {
+                        let gap = ChainEvent::BackfillGap {
+                            from: state.next,
+                            to: Some(head),
+                            error: e.to_string(),
+                        };
+                        let done = ChainEvent::Backfilled(state.next.saturating_sub(1));
+                        return Some((vec![gap, done], None));
+                    }
+                    tokio::time::sleep(BACKFILL_RETRY_DELAY * state.failures).await;
+                }
+            }
+        }
+    })
+    .flat_map(stream::iter)
+    .boxed()
+}
+
+/// Scans queued blocks one at a time, so each watcher gets its transfers in block order
+async fn transfer_scanner(
+    provider: Arc<Mutex<providerType>>,
+    event_sender: mpsc::Sender<RpcTypes>,
+    mut queue: mpsc::UnboundedReceiver<(Header, Vec<TransferWatch>)>,
+) {
+    while let Some((header, watchers)) = queue.recv().await {
+        let mut addresses: Vec<Address> = watchers
+            .iter()
+            .flat_map(|w| w.addresses.iter().copied())
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        let internal = watchers.iter().any(|w| w.internal);
+        let provider = provider.lock().await.clone();
+        let transfers = match scan_transfers(&provider, &header, &addresses, internal).await {
+            Ok(transfers) => transfers,
+            Err(e) => {
+                eprintln!("Error scanning block {}: {e}", header.number);
+                continue;
+            }
+        };
+        for watch in watchers {
+            for transfer in transfers.iter().filter(|t| {
+                (watch.internal || t.index == 0)
+                    && (watch.addresses.contains(&t.from) || watch.addresses.contains(&t.to))
+            }) {
+                let decoded = DecodedLog::from_transfer(watch.subid.clone(), transfer, &header);
+                let log = Log {
+                    block_number: decoded.block_number,
+                    block_hash: decoded.block_hash,
+                    block_timestamp: Some(header.timestamp),
+                    transaction_hash: decoded.tx_hash,
+                    log_index: decoded.log_index,
+                    ..Default::default()
+                };
+                let rpcevent = RpcTypes::UserLog {
+                    user: watch.user,
+                    sub_id: watch.subid.clone(),
+                    log,
+                    decoded,
+                };
+                if event_sender.send(rpcevent).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Value transfers to or from `addresses` in the block of `header`.
+/// The block is read by hash, so a reorg can't swap in a different block at the same height.
+/// Internal calls are read from `callTracer` traces, nodes without the debug API only yield top level transfers
+async fn scan_transfers(
+    provider: &providerType,
+    header: &Header,
+    addresses: &[Address],
+    internal: bool,
+) -> Result<Vec<NativeTransfer>, Box<dyn Error + Send + Sync>> {
+    let watched = |from: Address, to: Option<Address>| {
+        addresses.contains(&from) || to.is_some_and(|to| addresses.contains(&to))
+    };
+    let block = match provider.get_block_by_hash(header.hash).full().await? {
+        Some(block) => block,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut transfers = Vec::new();
+    for tx in block.transactions.txns() {
+        let (from, to, value) = (tx.inner.signer(), tx.to(), tx.value());
+        if value.is_zero() || !watched(from, to) {
+            continue;
+        }
+        if let Some(to) = to {
+            transfers.push(NativeTransfer {
+                from,
+                to,
+                value,
+                tx_hash: *tx.inner.tx_hash(),
+                index: 0,
+            });
+        }
+    }
+
+    // reverted transactions moved no value
+    if !transfers.is_empty() {
+        if let Some(receipts) = provider.get_block_receipts(header.hash.into()).await? {
+            let failed: Vec<B256> = receipts
+                .iter()
+                .filter(|r| !r.status())
+                .map(|r| r.transaction_hash)
+                .collect();
+            transfers.retain(|t| !failed.contains(&t.tx_hash));
+        }
+    }
+
+    if internal {
+        let options = GethDebugTracingOptions::call_tracer(CallConfig::default());
+        match provider
+            .debug_trace_block_by_hash(header.hash, options)
+            .await
+        {
+            Ok(traces) => {
+                for trace in traces {
+                    let (result, tx_hash) = match trace {
+                        TraceResult::Success { result, tx_hash } => (result, tx_hash),
+                        TraceResult::Error { .. } => continue,
+                    };
+                    let (frame, tx_hash) = match (result.try_into_call_frame(), tx_hash) {
+                        (Ok(frame), Some(tx_hash)) => (frame, tx_hash),
+                        _ => continue,
+                    };
+                    if frame.error.is_some() {
+                        continue;
+                    }
+                    let mut index = 0;
+                    let mut pending: Vec<&CallFrame> = frame.calls.iter().rev().collect();
+                    while let Some(call) = pending.pop() {
+                        index += 1;
+                        // value moved by a failed call is rolled back with everything below it
+                        if call.error.is_some() {
+                            continue;
+                        }
+                        pending.extend(call.calls.iter().rev());
+                        let value = call.value.unwrap_or_default();
+                        if value.is_zero()
+                            || call.typ == "DELEGATECALL"
+                            || !watched(call.from, call.to)
+                        {
+                            continue;
+                        }
+                        if let Some(to) = call.to {
+                            transfers.push(NativeTransfer {
+                                from: call.from,
+                                to,
+                                value,
+                                tx_hash,
+                                index,
+                            });
+                        }
+                    }
+                }
+            }
+            Err(e) => eprintln!(
+                "Internal transfers unavailable for block {}: {e}",
+                header.number
+            ),
+        }
+    }
+    Ok(transfers)
+}
+
 #[derive(Clone)]
 pub struct RPChandler {
     pub available_chains: Vec<usize>,
@@ -307,16 +1069,39 @@ impl RPChandler {
     ) -> Result<(), Box<dyn Error>> {
         let ziper = chainid.iter().zip(subscription.iter());
         for (chainid, sub) in ziper {
-            self.new_conn(chainid.clone(), sub.clone(), log_sender.clone())
+            self.new_conn(chainid.clone(), Some(sub.clone()), log_sender.clone())
                 .await?;
         }
         Ok(())
     }
 
+    /// Starts the worker of a configured chain without a subscription and returns its command channel
+    pub async fn connect(
+        &mut self,
+        chainid: usize,
+        log_sender: mpsc::Sender<RpcTypes>,
+    ) -> Result<mpsc::Sender<(SubscriptionType, oneshot::Sender<RpcTypes>)>, Box<dyn Error>> {
+        self.new_conn(chainid, None, log_sender).await?;
+        match self.channel(chainid) {
+            Some(channel) => Ok(channel),
+            None => Err(Box::new(RpcTypeError::ChainNotSupported)),
+        }
+    }
+
+    /// Command channel of a connected chain
+    pub fn channel(
+        &self,
+        chainid: usize,
+    ) -> Option<mpsc::Sender<(SubscriptionType, oneshot::Sender<RpcTypes>)>> {
+        self.chain_state
+            .get(&chainid)
+            .and_then(|state| state.channel.clone())
+    }
+
     async fn new_conn(
         &mut self,
         chainid: usize,
-        subscription: SubscriptionType,
+        subscription: Option<SubscriptionType>,
         log_sender: mpsc::Sender<RpcTypes>,
     ) -> Result<(), Box<dyn Error>> {
         let chain_state = self.chain_state.get_mut(&chainid);
@@ -342,3 +1127,49 @@ impl RPChandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{Bytes, LogData, U256};
+
+    #[test]
+    fn bare_signature_matches_and_delivers_three_topic_log() {
+        let (event_signature, event) =
+            eventTypes::log_event("Transfer(address,address,uint256)", "").unwrap();
+        let sub = SubscriptionType::Subscription {
+            user: Address::ZERO,
+            chainid: 1,
+            addresses: Vec::new(),
+            event_signature,
+            event: event.clone(),
+            topics: Default::default(),
+            filter: None,
+            from_block: None,
+            needs_block: false,
+        };
+        let (_, filter) = chainRpc::getFilter(&sub).unwrap();
+
+        // an ERC-721 transfer indexes the token id as topic3
+        let word = |n: u64| B256::from(U256::from(n));
+        let topics = vec![
+            keccak256("Transfer(address,address,uint256)"),
+            word(1),
+            word(2),
+            word(3),
+        ];
+        let log = Log {
+            inner: alloy::primitives::Log {
+                address: Address::ZERO,
+                data: LogData::new_unchecked(topics, Bytes::new()),
+            },
+            ..Default::default()
+        };
+        assert!(filter.matches(&log.inner));
+
+        let decoded = DecodedLog::from_log("sub".to_string(), &log, event.as_ref());
+        // delivered with the raw log and no decoded args
+        assert!(decoded.decode_error.is_none());
+        assert!(decoded.args.is_empty());
+    }
+}
@@ -1,4 +1,12 @@
-use crate::rpchandler::rpc_types::{RpcTypes, SubscriptionType};
+use crate::rpchandler::batch::{Batcher, DeliveryMode};
+use crate::rpchandler::eventTypes::DecodedLog;
+use crate::rpchandler::history::{
+    DeliveryFilter, DeliveryHistory, DeliveryKind, DeliveryRecord, DeliveryState, now_ms,
+};
+use crate::rpchandler::rpc_types::{RelayerTxResult, RpcTypes, SubscriptionType};
+use crate::rpchandler::scheduler::{ActionSpec, ScheduleSpec, Scheduler, SchedulerError};
+use crate::rpchandler::sinks::{EventSink, SinkError, VerificationStatus};
+use crate::rpchandler::template::{PayloadTemplate, TemplateContext};
 use crate::transactionTypes::*;
 use alloy::network::TransactionBuilder;
 use alloy::signers::k256::ecdsa::SigningKey;
@@ -9,11 +17,29 @@ use alloy::{
     rpc::types::Log,
 };
 use dashmap::DashMap;
+use serde_json::Value;
 use std::ops::Add;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::{collections::BTreeMap, default, error::Error};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::time::{self, Duration};
+
+const BATCH_FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+const SINK_MAX_ATTEMPTS: u32 = 3;
+/// Events kept per user until GetLogs drains them, the oldest are dropped first
+const MAX_EVENTS_PER_USER: usize = 1000;
+const SINK_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+/// A failed ownership challenge is re-run after this, doubling up to the max
+const VERIFY_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const VERIFY_MAX_BACKOFF: Duration = Duration::from_secs(3600);
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
+/// How often a step held back by its base fee limit is tried again, and how many times
+const DEFERRED_RETRY_INTERVAL: Duration = Duration::from_secs(15);
+const DEFERRED_MAX_ATTEMPTS: u32 = 40;
+/// Where schedules are persisted, overridden by the SCHEDULE_DB env var
+const DEFAULT_SCHEDULE_DB: &str = "data/schedules";
 
 pub struct UserInfo {
     pub signer: LocalSigner<SigningKey>,
@@ -26,27 +52,114 @@ pub struct UserUpdates {
     pub tx: String,
 }
 
+/// Progress of one trigger through a subscription's action plan
+#[derive(Clone)]
+struct ActionRun {
+    user: Address,
+    sub_id: String,
+    trigger: DecodedLog,
+    log: Log,
+    steps: Vec<RawTransaction>,
+    order: ActionOrder,
+    /// first step not sent yet
+    next: usize,
+    /// steps sent whose result hasn't come back
+    pending: usize,
+    failed: bool,
+    last: Option<RelayerTxResult>,
+}
+
 pub struct RelayerHandler {
     RpcCommand_sender: DashMap<usize, mpsc::Sender<(SubscriptionType, oneshot::Sender<RpcTypes>)>>,
     // log_receiver: Arc<Mutex<mpsc::Receiver<RpcTypes>>>,
     // command_receiver: Arc<Mutex<mpsc::Receiver<(RelayerCommand, oneshot::Sender<RpcTypes>)>>>,
     relayers: DashMap<Address, UserInfo>,
-    actions: DashMap<String, RawTransaction>,
+    actions: DashMap<String, ActionPlan>,
+    /// chain each log subscription listens on, revokes go to that worker
+    sub_chains: DashMap<String, usize>,
+    runs: DashMap<String, ActionRun>,
+    /// action delivery id to the run it belongs to
+    step_runs: DashMap<String, String>,
+    /// steps waiting for the base fee to drop: due time in unix millis, delivery id and step
+    deferred: Vec<(u64, String, usize)>,
+    templates: DashMap<String, PayloadTemplate>,
+    sinks: DashMap<String, Vec<Arc<dyn EventSink>>>,
+    sink_status: Arc<DashMap<(String, usize), VerificationStatus>>,
+    /// one queue per sink so a subscription's payloads arrive in order, items are delivery id and payload
+    sink_queues: DashMap<(String, usize), mpsc::UnboundedSender<(String, Value)>>,
+    batcher: Batcher,
+    owners: DashMap<String, Address>,
+    history: Arc<DeliveryHistory>,
     user_logs: Arc<DashMap<Address, Vec<UserUpdates>>>,
+    user_events: Arc<DashMap<Address, Vec<DecodedLog>>>,
+    scheduler: Scheduler,
 }
 
 impl RelayerHandler {
     pub fn new_handler(// mut log_receiver: mpsc::Receiver<RpcTypes>,
         // mut command_receiver: mpsc::Receiver<RelayerCommand>,
     ) -> Self {
-        RelayerHandler {
+        let path = std::env::var("SCHEDULE_DB").unwrap_or(DEFAULT_SCHEDULE_DB.to_string());
+        let scheduler = match Scheduler::open(&path) {
+            Ok(scheduler) => scheduler,
+            Err(e) => {
+                eprintln!("Schedules won't be persisted: {e}");
+                Scheduler::default()
+            }
+        };
+        Self::with_scheduler(scheduler)
+    }
+
+    fn with_scheduler(scheduler: Scheduler) -> Self {
+        let handler = RelayerHandler {
             RpcCommand_sender: Default::default(),
             // log_receiver: Arc::new(Mutex::new(log_receiver)),
             // command_receiver: Arc::new(Mutex::new(command_receiver)),
             relayers: Default::default(),
             actions: Default::default(),
+            sub_chains: Default::default(),
+            runs: Default::default(),
+            step_runs: Default::default(),
+            deferred: Default::default(),
+            templates: Default::default(),
+            sinks: Default::default(),
+            sink_status: Default::default(),
+            sink_queues: Default::default(),
+            batcher: Default::default(),
+            owners: Default::default(),
+            history: Default::default(),
             user_logs: Default::default(),
+            user_events: Default::default(),
+            scheduler,
+        };
+        // restored schedules come back paused, resuming needs a registered relayer
+        for schedule in handler.scheduler.all() {
+            let action = schedule.action;
+            let mut raw_tran = RawTransaction::new(
+                action.chainid,
+                action.target_address,
+                action.abi,
+                action.function_name,
+                action.params,
+            );
+            raw_tran.calls = action.calls;
+            raw_tran.guards = action.guards;
+            raw_tran.gas = action.gas;
+            handler
+                .actions
+                .insert(schedule.id.clone(), ActionPlan::single(raw_tran));
+            handler.owners.insert(schedule.id, schedule.user);
         }
+        handler
+    }
+
+    /// Routes actions for `chainid` to that chain's worker, chains are added before `run`
+    pub fn add_chain(
+        &mut self,
+        chainid: usize,
+        sender: mpsc::Sender<(SubscriptionType, oneshot::Sender<RpcTypes>)>,
+    ) {
+        self.RpcCommand_sender.insert(chainid, sender);
     }
 
     fn new_relayer(&mut self, address: String) -> Result<Address, Box<dyn Error>> {
@@ -78,6 +191,8 @@ impl RelayerHandler {
         mut log_receiver: mpsc::Receiver<RpcTypes>,
         mut command_receiver: mpsc::Receiver<(RelayerCommand, oneshot::Sender<RpcTypes>)>,
     ) -> Result<(), Box<dyn Error>> {
+        let mut flush_tick = time::interval(BATCH_FLUSH_INTERVAL);
+        let mut schedule_tick = time::interval(SCHEDULE_TICK);
         tokio::spawn(async move {
             loop {
                 tokio::select! {
@@ -91,6 +206,26 @@ impl RelayerHandler {
                             }
                         }
                     }
+                    _ = flush_tick.tick() => {
+                        for (sub_id, event_ids, payload) in self.batcher.expired() {
+                            self.deliver(&sub_id, event_ids, payload);
+                        }
+                    }
+                    _ = schedule_tick.tick() => {
+                        self.retry_deferred().await;
+                        let now = now_ms();
+                        for schedule in self.scheduler.due(now) {
+                            let trigger = RpcTypes::UserLog {
+                                user: schedule.user,
+                                sub_id: schedule.id.clone(),
+                                log: Log::default(),
+                                decoded: DecodedLog::from_schedule(&schedule, now),
+                            };
+                            if let Err(e) = self.handle_log(trigger).await {
+                                eprintln!("Error running schedule {}: {}", schedule.id, e);
+                            }
+                        }
+                    }
                 }
             }
         });
@@ -122,12 +257,20 @@ impl RelayerHandler {
 
             RelayerCommand::GetLogs { user } => {
                 if let Ok(addr) = Address::from_str(user.as_str()) {
-                    let logs = self.user_logs.clone();
-                    if let Some(mut map) = logs.get_mut(&addr) {
-                        let mut res_logs = map.clone();
+                    let mut res_logs = Vec::new();
+                    if let Some(mut map) = self.user_logs.get_mut(&addr) {
+                        res_logs = map.clone();
                         map.clear();
-                        res_receiver.send(RpcTypes::Logs { logs: res_logs });
                     }
+                    let mut res_events = Vec::new();
+                    if let Some(mut events) = self.user_events.get_mut(&addr) {
+                        res_events = events.clone();
+                        events.clear();
+                    }
+                    res_receiver.send(RpcTypes::Logs {
+                        logs: res_logs,
+                        events: res_events,
+                    });
                 }
             }
 
@@ -135,48 +278,196 @@ impl RelayerHandler {
                 user,
                 sub_id,
                 chainid,
-                target_address,
-                ABI,
-                function_name,
-                Params,
+                plan,
+                template,
+                sinks,
+                delivery,
             } => {
-                let raw_tran =
-                    RawTransaction::new(chainid, target_address, ABI, function_name, Params);
                 if let Ok(addr) = Address::from_str(user.as_str()) {
-                    self.actions.insert(sub_id.clone(), raw_tran);
-                    if let Some(mut userinfo) = self.relayers.get_mut(&addr) {
-                        userinfo.subs.push(sub_id);
-                    }
+                    self.sub_chains.insert(sub_id.clone(), chainid);
+                    self.define_action(addr, sub_id, plan, template, sinks, delivery);
                     res_receiver.send(RpcTypes::Response {
                         success: true,
                         message: "SuccessFully added".to_string(),
                     });
                 }
             }
+
+            RelayerCommand::CreateSchedule {
+                user,
+                spec,
+                action,
+                template,
+                sinks,
+                delivery,
+            } => {
+                let addr = match Address::from_str(user.as_str()) {
+                    Ok(addr) => addr,
+                    Err(_) => {
+                        res_receiver.send(RpcTypes::Response {
+                            success: false,
+                            message: RelayerError::InvalidAddress.to_string(),
+                        });
+                        return Ok(());
+                    }
+                };
+                match self.scheduler.create(addr, spec, action.clone()) {
+                    Ok(schedule) => {
+                        let mut raw_tran = RawTransaction::new(
+                            action.chainid,
+                            action.target_address,
+                            action.abi,
+                            action.function_name,
+                            action.params,
+                        );
+                        raw_tran.calls = action.calls;
+                        raw_tran.guards = action.guards;
+                        raw_tran.gas = action.gas;
+                        self.define_action(
+                            addr,
+                            schedule.id.clone(),
+                            ActionPlan::single(raw_tran),
+                            template,
+                            sinks,
+                            delivery,
+                        );
+                        res_receiver.send(RpcTypes::Schedule { schedule });
+                    }
+                    Err(e) => {
+                        res_receiver.send(RpcTypes::Response {
+                            success: false,
+                            message: e.to_string(),
+                        });
+                    }
+                }
+            }
+
+            RelayerCommand::PauseSchedule { user, id, paused } => {
+                let addr = match Address::from_str(user.as_str()) {
+                    Ok(addr) => addr,
+                    Err(_) => {
+                        res_receiver.send(RpcTypes::Response {
+                            success: false,
+                            message: SchedulerError::NotFound.to_string(),
+                        });
+                        return Ok(());
+                    }
+                };
+                if !paused && !self.relayers.contains_key(&addr) {
+                    res_receiver.send(RpcTypes::Response {
+                        success: false,
+                        message: RelayerError::NotRegistered.to_string(),
+                    });
+                    return Ok(());
+                }
+                match self.scheduler.set_paused(addr, &id, paused) {
+                    Ok(schedule) => {
+                        // a schedule restored after a restart isn't listed yet
+                        if let Some(mut userinfo) = self.relayers.get_mut(&addr) {
+                            if !userinfo.subs.contains(&id) {
+                                userinfo.subs.push(id);
+                            }
+                        }
+                        res_receiver.send(RpcTypes::Schedule { schedule })
+                    }
+                    Err(e) => res_receiver.send(RpcTypes::Response {
+                        success: false,
+                        message: e.to_string(),
+                    }),
+                };
+            }
             RelayerCommand::Revoke_Subscription { user, sub_id } => {
                 if let Ok(addr) = Address::from_str(user.as_str()) {
-                    if let Some(mut userinfo) = self.relayers.get_mut(&addr) {
-                        if let Some(tran) = self.actions.get(&sub_id) {
-                            let send = SubscriptionType::Revoke_Sub {
-                                user: addr,
-                                subs: sub_id.clone(),
-                            };
-                            self.actions.remove(&sub_id);
-                            userinfo.subs.retain(|s| s != &sub_id);
-                            let chainid = tran.chain_id;
-                            let mut res: RpcTypes = RpcTypes::Response {
-                                success: true,
-                                message: "Subscription revoked".to_string(),
-                            };
-                            if let Some(ch) = self.RpcCommand_sender.get_mut(&chainid) {
-                                let (ress, rc) = oneshot::channel::<RpcTypes>();
-                                ch.send((send, ress)).await?;
-                                res = rc.await.unwrap();
-                            }
+                    // schedules have no chain side subscription
+                    if self.scheduler.remove(addr, &sub_id).is_some() {
+                        self.remove_action(addr, &sub_id);
+                        res_receiver.send(RpcTypes::Response {
+                            success: true,
+                            message: "Schedule removed".to_string(),
+                        });
+                        return Ok(());
+                    }
+                    if self.owners.get(&sub_id).is_none_or(|owner| *owner != addr) {
+                        res_receiver.send(RpcTypes::Response {
+                            success: false,
+                            message: "Subscription not found".to_string(),
+                        });
+                        return Ok(());
+                    }
+                    self.remove_action(addr, &sub_id);
+                    let mut res: RpcTypes = RpcTypes::Response {
+                        success: true,
+                        message: "Subscription revoked".to_string(),
+                    };
+                    let ch = self
+                        .sub_chains
+                        .remove(&sub_id)
+                        .and_then(|(_, chainid)| self.RpcCommand_sender.get(&chainid))
+                        .map(|ch| ch.clone());
+                    let ch = match ch {
+                        Some(ch) => ch,
+                        None => {
                             res_receiver.send(res);
+                            return Ok(());
+                        }
+                    };
+                    let send = SubscriptionType::Revoke_Sub {
+                        user: addr,
+                        subs: sub_id.clone(),
+                    };
+                    // the chain worker may be waiting to hand this loop a log, so its reply is awaited apart
+                    tokio::spawn(async move {
+                        let (ress, rc) = oneshot::channel::<RpcTypes>();
+                        if ch.send((send, ress)).await.is_ok() {
+                            if let Ok(reply) = rc.await {
+                                res = reply;
+                            }
                         }
+                        res_receiver.send(res);
+                    });
+                }
+            }
+
+            RelayerCommand::GetSubscription { user, sub_id } => {
+                // sink only subscriptions have an owner but no relayer
+                let owned = match Address::from_str(user.as_str()) {
+                    Ok(addr) => self.owners.get(&sub_id).is_some_and(|owner| *owner == addr),
+                    Err(_) => false,
+                };
+                if !owned {
+                    res_receiver.send(RpcTypes::Response {
+                        success: false,
+                        message: "Subscription not found".to_string(),
+                    });
+                    return Ok(());
+                }
+                let mut sinks = Vec::new();
+                if let Some(subs) = self.sinks.get(&sub_id) {
+                    for (i, sink) in subs.iter().enumerate() {
+                        let status = match self.sink_status.get(&(sub_id.clone(), i)) {
+                            Some(status) => status.clone(),
+                            None => VerificationStatus::Pending,
+                        };
+                        sinks.push((sink.destination(), status));
                     }
                 }
+                res_receiver.send(RpcTypes::SubscriptionInfo { sub_id, sinks });
+            }
+
+            RelayerCommand::ListDeliveries { user, filter } => {
+                let records = match Address::from_str(user.as_str()) {
+                    Ok(addr) => self.history.list(addr, &filter),
+                    Err(_) => Vec::new(),
+                };
+                res_receiver.send(RpcTypes::Deliveries { records });
+            }
+
+            RelayerCommand::GetDelivery { user, id } => {
+                let records = match Address::from_str(user.as_str()) {
+                    Ok(addr) => self.history.get(addr, &id).into_iter().collect(),
+                    Err(_) => Vec::new(),
+                };
+                res_receiver.send(RpcTypes::Deliveries { records });
             }
 
             RelayerCommand::Get_RalyerInfo { user } => {
@@ -198,46 +489,525 @@ impl RelayerHandler {
         let mut addr = Address::default();
         let mut subid = String::new();
         let mut Userlog = Log::default();
+        let mut trigger = DecodedLog::default();
         match log {
-            RpcTypes::UserLog { user, sub_id, log } => {
+            RpcTypes::UserLog {
+                user,
+                sub_id,
+                log,
+                decoded,
+            } => {
                 addr = user;
                 subid = sub_id;
                 Userlog = log;
+                trigger = decoded;
+            }
+            RpcTypes::TxResult {
+                delivery_id,
+                result,
+                ..
+            } => {
+                self.handle_tx_result(delivery_id, result).await;
+                return Ok(());
+            }
+            RpcTypes::BackfillGap {
+                user,
+                sub_id,
+                from,
+                to,
+                error,
+            } => {
+                self.record_gap(user, &sub_id, from, to, error);
+                return Ok(());
             }
             _ => {}
         }
-        let mut transaction: RawTransaction = RawTransaction::default();
-        if let Some(raw_tran) = self.actions.get(&subid) {
-            transaction = raw_tran.clone();
+
+        // templates reading tx fields are rendered once the relayer transaction settles
+        let waits_for_tx = match self.templates.get(&subid) {
+            Some(template) => template.uses_tx(),
+            None => false,
+        };
+
+        let mut plan = self
+            .actions
+            .get(&subid)
+            .map(|p| p.clone())
+            .unwrap_or_default();
+        plan.steps
+            .retain(|t| !(trigger.historical && t.skip_historical));
+        if plan.steps.is_empty() || !waits_for_tx {
+            self.record_event(addr, trigger.clone(), None);
+        }
+        if !plan.steps.is_empty() {
+            let run = ActionRun {
+                user: addr,
+                sub_id: subid,
+                trigger,
+                log: Userlog,
+                steps: plan.steps,
+                order: plan.order,
+                next: 0,
+                pending: 0,
+                failed: false,
+                last: None,
+            };
+            let run_id = format!("{:016x}", rand::random::<u64>());
+            self.advance(run_id, run).await;
+        }
+        Ok(())
+    }
+
+    async fn handle_tx_result(&mut self, delivery_id: String, result: RelayerTxResult) {
+        if result.deferred && self.defer_step(&delivery_id, &result) {
+            return;
+        }
+        if !result.error.is_empty() && !result.skipped {
+            eprintln!("Relayer transaction {delivery_id} failed: {}", result.error);
+        }
+        self.history.update(&delivery_id, |record| {
+            if !result.hash.is_empty() {
+                record.tx_hash = Some(result.hash.clone());
+            }
+            record.state = if result.skipped {
+                DeliveryState::Skipped
+            } else if result.success {
+                DeliveryState::Succeeded
+            } else {
+                DeliveryState::Failed
+            };
+            record.error = result.error.clone();
+        });
+        let run_id = match self.step_runs.remove(&delivery_id) {
+            Some((_, run_id)) => run_id,
+            None => return,
+        };
+        self.settle_step(run_id, result).await;
+    }
+
+    /// Counts a finished step against its run and moves the run on
+    async fn settle_step(&mut self, run_id: String, result: RelayerTxResult) {
+        // gone when the subscription was revoked meanwhile
+        if let Some((_, mut run)) = self.runs.remove(&run_id) {
+            run.pending -= 1;
+            // a skipped step wasn't needed, it doesn't stop the steps after it
+            run.failed |= !result.success && !result.skipped;
+            run.last = Some(result);
+            self.advance(run_id, run).await;
+        }
+    }
+
+    /// Sends the steps a run can start now, all of them for parallel plans and the
+    /// next one for sequential plans. Once nothing is in flight and no step is left
+    /// the event is recorded with the last transaction outcome
+    async fn advance(&mut self, run_id: String, mut run: ActionRun) {
+        while run.next < run.steps.len() {
+            let step = run.next;
+            match run.order {
+                ActionOrder::Sequential { .. } if run.pending > 0 => break,
+                ActionOrder::Sequential {
+                    stop_on_failure: true,
+                } if run.failed => {
+                    self.skip_step(&run, step);
+                }
+                _ => match self.send_step(&run, step, &run_id).await {
+                    Ok(()) => run.pending += 1,
+                    Err(result) => {
+                        run.failed = true;
+                        run.last = Some(result);
+                    }
+                },
+            }
+            run.next += 1;
+        }
+        if run.pending > 0 {
+            self.runs.insert(run_id, run);
+            return;
+        }
+        let waits_for_tx = match self.templates.get(&run.sub_id) {
+            Some(template) => template.uses_tx(),
+            None => false,
+        };
+        if waits_for_tx {
+            self.record_event(run.user, run.trigger, run.last.as_ref());
         }
+    }
 
-        if let Some(wallet) = self.relayers.get_mut(&addr) {
-            if let Ok(mut tran) = transaction.clone().build_transaction(Userlog) {
-                let s = wallet.signer.clone();
-                let db = self.user_logs.clone();
-                tran = tran
-                    .with_from(s.address())
-                    .with_chain_id(transaction.chain_id as u64);
+    /// Hands one step to the worker of its chain, the chain worker reads the view
+    /// calls and encodes the call
+    async fn send_step(
+        &self,
+        run: &ActionRun,
+        step: usize,
+        run_id: &str,
+    ) -> Result<(), RelayerTxResult> {
+        let delivery_id = self.history.start(
+            run.user,
+            &run.sub_id,
+            &run.trigger.event_id(),
+            DeliveryKind::Action,
+            run.steps[step].destination(),
+        );
+        self.history.update(&delivery_id, |record| {
+            record.step = Some(step);
+            record.attempts = 1;
+        });
+        self.dispatch_step(run, step, run_id, delivery_id).await
+    }
 
+    /// Sends a step under an existing delivery record, failures mark the record failed
+    async fn dispatch_step(
+        &self,
+        run: &ActionRun,
+        step: usize,
+        run_id: &str,
+        delivery_id: String,
+    ) -> Result<(), RelayerTxResult> {
+        let action = &run.steps[step];
+        let signer = self.relayers.get(&run.user).map(|info| info.signer.clone());
+        let ch = self
+            .RpcCommand_sender
+            .get(&action.chain_id)
+            .map(|ch| ch.clone());
+        let failure = match (signer, ch) {
+            (None, _) => String::from("User has no relayer"),
+            (_, None) => String::from("Chain is not connected"),
+            (Some(signer), Some(ch)) => {
                 let res = SubscriptionType::Transaction {
-                    user: addr.clone(),
-                    signer: s,
-                    tx: tran,
-                    db: db.clone(),
+                    user: run.user,
+                    signer,
+                    action: action.clone(),
+                    log: run.log.clone(),
+                    db: self.user_logs.clone(),
+                    sub_id: run.sub_id.clone(),
+                    trigger: run.trigger.clone(),
+                    delivery_id: delivery_id.clone(),
                 };
+                let (sender, _rec) = oneshot::channel::<RpcTypes>();
+                match ch.try_send((res, sender)) {
+                    Ok(()) => {
+                        self.step_runs.insert(delivery_id, run_id.to_string());
+                        return Ok(());
+                    }
+                    // the chain worker may itself be waiting on this loop, so a full queue is
+                    // waited on from a task instead
+                    Err(TrySendError::Full(cmd)) => {
+                        self.step_runs
+                            .insert(delivery_id.clone(), run_id.to_string());
+                        let history = self.history.clone();
+                        tokio::spawn(async move {
+                            if ch.send(cmd).await.is_err() {
+                                history.update(&delivery_id, |record| {
+                                    record.state = DeliveryState::Failed;
+                                    record.error = String::from("Chain worker stopped");
+                                });
+                            }
+                        });
+                        return Ok(());
+                    }
+                    Err(TrySendError::Closed(_)) => String::from("Chain worker stopped"),
+                }
+            }
+        };
+        self.history.update(&delivery_id, |record| {
+            record.state = DeliveryState::Failed;
+            record.error = failure.clone();
+        });
+        Err(RelayerTxResult {
+            error: failure,
+            ..Default::default()
+        })
+    }
+
+    /// Queues a step its base fee limit held back, false once it ran out of attempts
+    fn defer_step(&mut self, delivery_id: &str, result: &RelayerTxResult) -> bool {
+        let mut step = None;
+        let mut attempts = 0;
+        self.history.update(delivery_id, |record| {
+            step = record.step;
+            attempts = record.attempts;
+            record.error = result.error.clone();
+        });
+        let step = match step {
+            Some(step) if attempts < DEFERRED_MAX_ATTEMPTS => step,
+            _ => return false,
+        };
+        let due = now_ms() + DEFERRED_RETRY_INTERVAL.as_millis() as u64;
+        self.deferred.push((due, delivery_id.to_string(), step));
+        true
+    }
+
+    /// Sends the deferred steps that are due again
+    async fn retry_deferred(&mut self) {
+        let now = now_ms();
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.deferred)
+            .into_iter()
+            .partition(|(at, _, _)| *at <= now);
+        self.deferred = waiting;
+        for (_, delivery_id, step) in due {
+            let run_id = self.step_runs.get(&delivery_id).map(|id| id.clone());
+            let run = run_id
+                .as_ref()
+                .and_then(|id| self.runs.get(id))
+                .map(|run| run.clone());
+            let (run_id, run) = match (run_id, run) {
+                (Some(run_id), Some(run)) => (run_id, run),
+                _ => {
+                    self.step_runs.remove(&delivery_id);
+                    self.history.update(&delivery_id, |record| {
+                        record.state = DeliveryState::Skipped;
+                        record.error = String::from("Subscription revoked");
+                    });
+                    continue;
+                }
+            };
+            self.history
+                .update(&delivery_id, |record| record.attempts += 1);
+            if let Err(result) = self
+                .dispatch_step(&run, step, &run_id, delivery_id.clone())
+                .await
+            {
+                self.step_runs.remove(&delivery_id);
+                self.settle_step(run_id, result).await;
+            }
+        }
+    }
+
+    /// Records a failed delivery for the blocks a backfill couldn't read, their logs are missing from the subscription
+    fn record_gap(&self, user: Address, sub_id: &str, from: u64, to: Option<u64>, error: String) {
+        let range = match to {
+            Some(to) => format!("blocks {from}-{to}"),
+            None => format!("blocks {from}-head"),
+        };
+        let delivery_id = self
+            .history
+            .start(user, sub_id, "", DeliveryKind::Backfill, range);
+        self.history.update(&delivery_id, |record| {
+            record.state = DeliveryState::Failed;
+            record.error = format!("Backfill missed these blocks: {error}");
+        });
+    }
+
+    /// Records a sequential step that isn't sent because an earlier one failed
+    fn skip_step(&self, run: &ActionRun, step: usize) {
+        let delivery_id = self.history.start(
+            run.user,
+            &run.sub_id,
+            &run.trigger.event_id(),
+            DeliveryKind::Action,
+            run.steps[step].destination(),
+        );
+        self.history.update(&delivery_id, |record| {
+            record.step = Some(step);
+            record.state = DeliveryState::Skipped;
+            record.error = String::from("An earlier step failed");
+        });
+    }
+
+    /// Renders the subscription's payload template, stores the event for GetLogs
+    /// and fans it out to the subscription's sinks
+    fn record_event(&mut self, user: Address, mut event: DecodedLog, tx: Option<&RelayerTxResult>) {
+        if let Some(template) = self.templates.get(&event.sub_id) {
+            let payload = template.render(&TemplateContext { log: &event, tx });
+            event.payload = Some(payload);
+        }
+        if self.sinks.contains_key(&event.sub_id) {
+            let payload = match &event.payload {
+                Some(payload) => payload.clone(),
+                None => event.to_json(),
+            };
+            let block = event.block_number.map(|n| (n, event.block_hash));
+            for (event_ids, ready) in
+                self.batcher
+                    .push(&event.sub_id, event.event_id(), payload, block)
+            {
+                self.deliver(&event.sub_id, event_ids, ready);
+            }
+        }
+        let mut events = self.user_events.entry(user).or_default();
+        events.push(event);
+        if events.len() > MAX_EVENTS_PER_USER {
+            let excess = events.len() - MAX_EVENTS_PER_USER;
+            events.drain(..excess);
+        }
+    }
+
+    /// Registers what a subscription or schedule does when it fires
+    fn define_action(
+        &mut self,
+        user: Address,
+        sub_id: String,
+        plan: ActionPlan,
+        template: Option<PayloadTemplate>,
+        sinks: Vec<Arc<dyn EventSink>>,
+        delivery: DeliveryMode,
+    ) {
+        self.actions.insert(sub_id.clone(), plan);
+        if let Some(template) = template {
+            self.templates.insert(sub_id.clone(), template);
+        }
+        if !sinks.is_empty() {
+            self.verify_sinks(&sub_id, &sinks);
+            self.sinks.insert(sub_id.clone(), sinks);
+        }
+        self.batcher.set_mode(sub_id.clone(), delivery);
+        self.owners.insert(sub_id.clone(), user);
+        if let Some(mut userinfo) = self.relayers.get_mut(&user) {
+            userinfo.subs.push(sub_id);
+        }
+    }
 
-                if let Some(ch) = self.RpcCommand_sender.get_mut(&transaction.chain_id) {
-                    let (sender, _rec) = oneshot::channel::<RpcTypes>();
-                    match ch.send((res, sender)).await {
-                        Ok(_) => {}
+    /// Drops everything `define_action` registered, pending batches are flushed first
+    fn remove_action(&mut self, user: Address, sub_id: &str) {
+        self.actions.remove(sub_id);
+        self.runs.retain(|_, run| run.sub_id != sub_id);
+        self.templates.remove(sub_id);
+        if let Some((event_ids, payload)) = self.batcher.remove(sub_id) {
+            self.deliver(sub_id, event_ids, payload);
+        }
+        self.sinks.remove(sub_id);
+        self.sink_status.retain(|(id, _), _| id != sub_id);
+        // queued payloads are still sent, the sink tasks end once their queue is empty
+        self.sink_queues.retain(|(id, _), _| id != sub_id);
+        self.owners.remove(sub_id);
+        if let Some(mut userinfo) = self.relayers.get_mut(&user) {
+            userinfo.subs.retain(|s| s != sub_id);
+        }
+    }
+
+    /// Starts the ownership handshake for sinks that need one, the rest are usable right away
+    fn verify_sinks(&self, sub_id: &str, sinks: &[Arc<dyn EventSink>]) {
+        for (i, sink) in sinks.iter().enumerate() {
+            let key = (sub_id.to_string(), i);
+            if !sink.needs_verification() {
+                self.sink_status.insert(key, VerificationStatus::Verified);
+                continue;
+            }
+            self.sink_status
+                .insert(key.clone(), VerificationStatus::Pending);
+            let sink = sink.clone();
+            let sink_status = self.sink_status.clone();
+            tokio::spawn(async move {
+                let mut backoff = VERIFY_RETRY_BACKOFF;
+                loop {
+                    let status = match sink.verify().await {
+                        Ok(_) => VerificationStatus::Verified,
                         Err(e) => {
-                            eprintln!("Error sending transaction: {}", e);
+                            eprintln!("Verification of {} failed: {e}", sink.destination());
+                            VerificationStatus::Failed(e.to_string())
                         }
+                    };
+                    let verified = matches!(status, VerificationStatus::Verified);
+                    // the subscription may have been revoked meanwhile
+                    match sink_status.get_mut(&key) {
+                        Some(mut current) => *current = status,
+                        None => return,
+                    }
+                    if verified {
+                        return;
+                    }
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(VERIFY_MAX_BACKOFF);
+                }
+            });
+        }
+    }
+
+    /// Hands a payload to the subscription's verified sinks, retrying failed attempts
+    fn deliver(&self, sub_id: &str, event_id: String, payload: Value) {
+        let user = match self.owners.get(sub_id) {
+            Some(user) => *user,
+            None => return,
+        };
+        if let Some(sinks) = self.sinks.get(sub_id) {
+            for (i, sink) in sinks.iter().enumerate() {
+                let unverified = match self.sink_status.get(&(sub_id.to_string(), i)).as_deref() {
+                    Some(VerificationStatus::Verified) => None,
+                    Some(VerificationStatus::Failed(e)) => {
+                        Some(format!("Sink failed verification: {e}"))
                     }
+                    _ => Some(String::from("Sink verification pending")),
+                };
+                let id = self.history.start(
+                    user,
+                    sub_id,
+                    &event_id,
+                    DeliveryKind::Sink,
+                    sink.destination(),
+                );
+                if let Some(reason) = unverified {
+                    self.history.update(&id, |record| {
+                        record.state = DeliveryState::Skipped;
+                        record.error = reason;
+                    });
+                    continue;
+                }
+                let queue = self.sink_queue(sub_id, i, sink);
+                if queue.send((id.clone(), payload.clone())).is_err() {
+                    self.history.update(&id, |record| {
+                        record.state = DeliveryState::Failed;
+                        record.error = String::from("Sink queue closed");
+                    });
                 }
             }
         }
-        Ok(())
+    }
+
+    /// Queue of a sink, its task sends one payload at a time with retries
+    fn sink_queue(
+        &self,
+        sub_id: &str,
+        index: usize,
+        sink: &Arc<dyn EventSink>,
+    ) -> mpsc::UnboundedSender<(String, Value)> {
+        self.sink_queues
+            .entry((sub_id.to_string(), index))
+            .or_insert_with(|| {
+                let (queue, mut jobs) = mpsc::unbounded_channel::<(String, Value)>();
+                let history = self.history.clone();
+                let sink = sink.clone();
+                tokio::spawn(async move {
+                    while let Some((id, payload)) = jobs.recv().await {
+                        send_to_sink(sink.as_ref(), &history, &id, &payload).await;
+                    }
+                });
+                queue
+            })
+            .clone()
+    }
+}
+
+/// Delivers one payload, retrying failed attempts with a growing backoff
+async fn send_to_sink(sink: &dyn EventSink, history: &DeliveryHistory, id: &str, payload: &Value) {
+    for attempt in 1..=SINK_MAX_ATTEMPTS {
+        let res = sink.deliver(payload).await;
+        let done = res.is_ok() || attempt == SINK_MAX_ATTEMPTS;
+        history.update(id, |record| {
+            record.attempts = attempt;
+            match &res {
+                Ok(status) => {
+                    record.state = DeliveryState::Succeeded;
+                    record.http_status = *status;
+                    record.error.clear();
+                }
+                Err(e) => {
+                    if let SinkError::Status(code) = e {
+                        record.http_status = Some(*code);
+                    }
+                    record.error = e.to_string();
+                    if done {
+                        record.state = DeliveryState::Failed;
+                    }
+                }
+            }
+        });
+        if done {
+            if let Err(e) = res {
+                eprintln!("Delivery to {} failed: {e}", sink.destination());
+            }
+            return;
+        }
+        time::sleep(SINK_RETRY_BACKOFF * attempt).await;
     }
 }
 
@@ -251,11 +1021,12 @@ pub enum RelayerCommand {
     DefineRelayerAction {
         user: String,
         sub_id: String,
+        /// chain the subscription listens on
         chainid: usize,
-        target_address: String,
-        ABI: String,
-        function_name: String,
-        Params: Vec<(usize, String)>,
+        plan: ActionPlan,
+        template: Option<PayloadTemplate>,
+        sinks: Vec<Arc<dyn EventSink>>,
+        delivery: DeliveryMode,
     },
     Revoke_Subscription {
         user: String,
@@ -264,4 +1035,83 @@ pub enum RelayerCommand {
     Get_RalyerInfo {
         user: String,
     },
+    GetSubscription {
+        user: String,
+        sub_id: String,
+    },
+    ListDeliveries {
+        user: String,
+        filter: DeliveryFilter,
+    },
+    GetDelivery {
+        user: String,
+        id: String,
+    },
+    CreateSchedule {
+        user: String,
+        spec: ScheduleSpec,
+        action: ActionSpec,
+        template: Option<PayloadTemplate>,
+        sinks: Vec<Arc<dyn EventSink>>,
+        delivery: DeliveryMode,
+    },
+    PauseSchedule {
+        user: String,
+        id: String,
+        paused: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpchandler::sinks::SinkSpec;
+
+    #[tokio::test]
+    async fn sink_only_subscription_is_readable_by_its_owner() {
+        let mut handler = RelayerHandler::with_scheduler(Scheduler::default());
+        let user = Address::repeat_byte(1);
+        let sink = SinkSpec::Nats {
+            url: "nats://127.0.0.1:4222".to_string(),
+            subject: "hooks".to_string(),
+        }
+        .build()
+        .unwrap();
+        let (tx, _) = oneshot::channel();
+        let define = RelayerCommand::DefineRelayerAction {
+            user: user.to_string(),
+            sub_id: "sub".to_string(),
+            chainid: 1,
+            plan: ActionPlan::default(),
+            template: None,
+            sinks: vec![sink],
+            delivery: DeliveryMode::Immediate,
+        };
+        handler.handle_command(define, tx).await.unwrap();
+
+        let get = |user: Address| RelayerCommand::GetSubscription {
+            user: user.to_string(),
+            sub_id: "sub".to_string(),
+        };
+        let (tx, rx) = oneshot::channel();
+        handler.handle_command(get(user), tx).await.unwrap();
+        match rx.await.unwrap() {
+            RpcTypes::SubscriptionInfo { sub_id, sinks } => {
+                assert_eq!(sub_id, "sub");
+                assert_eq!(sinks.len(), 1);
+                assert!(matches!(sinks[0].1, VerificationStatus::Verified));
+            }
+            _ => panic!("expected the subscription"),
+        }
+
+        let (tx, rx) = oneshot::channel();
+        handler
+            .handle_command(get(Address::repeat_byte(2)), tx)
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.await.unwrap(),
+            RpcTypes::Response { success: false, .. }
+        ));
+    }
 }
@@ -1,12 +1,20 @@
 use std::ops::Add;
 use std::sync::Arc;
 
+use crate::rpchandler::eventTypes::DecodedLog;
+use crate::rpchandler::expr::Expr;
+use crate::rpchandler::history::DeliveryRecord;
 use crate::rpchandler::relayer::UserUpdates;
-use alloy::primitives::{Address, ChainId};
-use alloy::rpc::types::{Filter, Log, TransactionRequest};
+use crate::rpchandler::scheduler::Schedule;
+use crate::rpchandler::sinks::VerificationStatus;
+use crate::rpchandler::transactionTypes::RawTransaction;
+use alloy::json_abi::{Event, JsonAbi};
+use alloy::primitives::{Address, B256, ChainId, FixedBytes};
+use alloy::rpc::types::{Filter, Header, Log, Transaction, TransactionRequest};
 use alloy::signers::k256::ecdsa::SigningKey;
 use alloy::signers::local::LocalSigner;
 use dashmap::DashMap;
+use futures::stream::BoxStream;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use thiserror::Error;
@@ -17,14 +25,52 @@ pub enum SubscriptionType {
     Subscription {
         user: Address,
         chainid: usize,
-        address: Address,
+        /// contracts to watch, empty for any address
+        addresses: Vec<Address>,
+        /// canonical signature, `Transfer(address,address,uint256)`
         event_signature: String,
+        event: Option<Event>,
+        /// accepted values for topic1 to topic3, empty matches anything
+        topics: [Vec<B256>; 3],
+        /// predicate over the decoded log, only matching logs are delivered
+        filter: Option<Expr>,
+        /// past logs since this block are delivered before the live ones
+        from_block: Option<u64>,
+        /// the action reads `block.*`, so each log's block header is fetched
+        needs_block: bool,
+    },
+    /// Fires on every `every`-th new block, 0 and 1 fire on each block
+    NewHeads {
+        user: Address,
+        chainid: usize,
+        every: u64,
+    },
+    /// Pending transactions sent to one of `to` and calling one of `selectors`, empty lists match anything
+    PendingTx {
+        user: Address,
+        chainid: usize,
+        to: Vec<Address>,
+        selectors: Vec<FixedBytes<4>>,
+        abi: Option<JsonAbi>,
+    },
+    /// Native value transfers to or from `addresses`, read from each new block
+    Transfers {
+        user: Address,
+        chainid: usize,
+        addresses: Vec<Address>,
+        /// also scan internal calls, needs `debug_traceBlockByNumber` on the node
+        internal: bool,
     },
     Transaction {
         user: Address,
         signer: LocalSigner<SigningKey>,
-        tx: TransactionRequest,
+        action: RawTransaction,
+        /// the trigger's log, for `topicN` parameters
+        log: Log,
         db: Arc<DashMap<Address, Vec<UserUpdates>>>,
+        sub_id: String,
+        trigger: DecodedLog,
+        delivery_id: String,
     },
     Revoke_Sub {
         user: Address,
@@ -32,12 +78,31 @@ pub enum SubscriptionType {
     },
 }
 
+/// Item of a chain connection's subscription streams
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    Log(Log),
+    Block(Header),
+    PendingTx(Transaction),
+    /// Blocks a `from_block` backfill couldn't read, up to `to` or to the head when it's unknown
+    BackfillGap {
+        from: u64,
+        to: Option<u64>,
+        error: String,
+    },
+    /// End of a `from_block` backfill, carries the last block it read
+    Backfilled(u64),
+}
+
+pub type ChainStream = BoxStream<'static, ChainEvent>;
+
 #[derive(Clone)]
 pub enum RpcTypes {
     UserLog {
         user: Address,
         sub_id: String,
         log: Log,
+        decoded: DecodedLog,
     },
     Response {
         success: bool,
@@ -45,7 +110,45 @@ pub enum RpcTypes {
     },
     Logs {
         logs: Vec<UserUpdates>,
+        events: Vec<DecodedLog>,
+    },
+    Schedule {
+        schedule: Schedule,
+    },
+    SubscriptionInfo {
+        sub_id: String,
+        sinks: Vec<(String, VerificationStatus)>,
+    },
+    TxResult {
+        user: Address,
+        sub_id: String,
+        trigger: DecodedLog,
+        delivery_id: String,
+        result: RelayerTxResult,
+    },
+    Deliveries {
+        records: Vec<DeliveryRecord>,
     },
+    /// Logs of a subscription's backfill range that were never delivered
+    BackfillGap {
+        user: Address,
+        sub_id: String,
+        from: u64,
+        to: Option<u64>,
+        error: String,
+    },
+}
+
+/// Outcome of a relayer transaction sent for a subscription
+#[derive(Clone, Debug, Default)]
+pub struct RelayerTxResult {
+    pub hash: String,
+    pub success: bool,
+    /// not sent because a guard didn't hold, `error` names it
+    pub skipped: bool,
+    /// held back by the action's base fee limit, the relayer sends it again later
+    pub deferred: bool,
+    pub error: String,
 }
 
 /// Errors for RPC operations           
@@ -60,6 +163,20 @@ pub enum RpcTypeError {
     NoSubscriptionFound,
     #[error("Error while subscription")]
     SubscriptionError,
+    #[error("Invalid event ABI")]
+    InvalidEventAbi,
+    #[error("Invalid function ABI")]
+    InvalidFunctionAbi,
+    #[error("Invalid event signature: {0}")]
+    InvalidEventSignature(String),
+    #[error("Log does not match the event ABI")]
+    LogDecodeError,
+    #[error("Invalid topic filter: {0}")]
+    InvalidTopicFilter(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Invalid function selector {0}")]
+    InvalidSelector(String),
 }
 
 #[derive(Clone)]
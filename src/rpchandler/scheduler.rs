@@ -0,0 +1,476 @@
+use crate::rpchandler::history::now_ms;
+use crate::rpchandler::transactionTypes::{GasPolicy, ViewCall};
+use alloy::primitives::Address;
+use dashmap::DashMap;
+use rocksdb::{DB, IteratorMode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Shortest accepted interval, cron expressions have the same one minute resolution
+pub const MIN_INTERVAL_SECS: u64 = 60;
+/// A cron expression that doesn't fire within this many days is rejected
+const CRON_SEARCH_DAYS: i64 = 366 * 5;
+const MINUTE_MS: u64 = 60_000;
+/// Why schedules loaded from the store start paused
+const RESTORED_REASON: &str = "Restarted, the relayer key, payload template and sinks aren't persisted. \
+     Resuming after registering again runs the action alone";
+const DAY_MS: u64 = 86_400_000;
+
+#[derive(Error, Debug)]
+pub enum SchedulerError {
+    #[error("Invalid cron expression: {0}")]
+    InvalidCron(String),
+    #[error("Interval must be at least {MIN_INTERVAL_SECS} seconds")]
+    IntervalTooShort,
+    #[error("Schedule not found")]
+    NotFound,
+    #[error("Schedule store error: {0}")]
+    Store(String),
+}
+
+/// When a schedule fires
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ScheduleSpec {
+    Interval {
+        secs: u64,
+    },
+    /// five field cron expression, evaluated in UTC
+    Cron {
+        expr: String,
+    },
+}
+
+impl ScheduleSpec {
+    pub fn validate(&self) -> Result<(), SchedulerError> {
+        match self {
+            ScheduleSpec::Interval { secs } if *secs < MIN_INTERVAL_SECS => {
+                Err(SchedulerError::IntervalTooShort)
+            }
+            ScheduleSpec::Interval { .. } => Ok(()),
+            ScheduleSpec::Cron { expr } => match CronExpr::parse(expr)?.next_after(now_ms()) {
+                Some(_) => Ok(()),
+                None => Err(SchedulerError::InvalidCron(format!("{expr} never fires"))),
+            },
+        }
+    }
+
+    /// First run strictly after `after`, unix millis
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        match self {
+            ScheduleSpec::Interval { secs } => Some(after + secs * 1000),
+            ScheduleSpec::Cron { expr } => CronExpr::parse(expr).ok()?.next_after(after),
+        }
+    }
+}
+
+/// Relayer action of a schedule, kept so it can be restored after a restart
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActionSpec {
+    pub chainid: usize,
+    pub target_address: String,
+    pub abi: String,
+    pub function_name: String,
+    pub params: Vec<(usize, String)>,
+    #[serde(default)]
+    pub calls: Vec<ViewCall>,
+    #[serde(default)]
+    pub guards: Vec<String>,
+    #[serde(default)]
+    pub gas: GasPolicy,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: String,
+    pub user: Address,
+    pub spec: ScheduleSpec,
+    pub action: ActionSpec,
+    pub paused: bool,
+    /// set when the service paused the schedule rather than its user
+    #[serde(default)]
+    pub paused_reason: String,
+    /// unix millis, 0 while paused
+    pub next_run: u64,
+    pub last_run: Option<u64>,
+    pub runs: u64,
+}
+
+/// Persisted schedules and their next run times.
+/// Runs missed while the service was down are coalesced into one run at startup.
+pub struct Scheduler {
+    schedules: DashMap<String, Schedule>,
+    store: Option<DB>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler {
+            schedules: Default::default(),
+            store: None,
+        }
+    }
+}
+
+impl Scheduler {
+    /// Opens the schedule store at `path` and loads the saved schedules
+    pub fn open(path: &str) -> Result<Self, SchedulerError> {
+        let db = DB::open_default(path).map_err(|e| SchedulerError::Store(e.to_string()))?;
+        let schedules = DashMap::new();
+        for entry in db.iterator(IteratorMode::Start) {
+            let (_, value) = entry.map_err(|e| SchedulerError::Store(e.to_string()))?;
+            match serde_json::from_slice::<Schedule>(&value) {
+                Ok(schedule) => {
+                    let schedule = restore(schedule);
+                    schedules.insert(schedule.id.clone(), schedule);
+                }
+                Err(e) => eprintln!("Skipping unreadable schedule: {e}"),
+            }
+        }
+        let scheduler = Scheduler {
+            schedules,
+            store: Some(db),
+        };
+        for schedule in scheduler.schedules.iter() {
+            scheduler.save(&schedule);
+        }
+        Ok(scheduler)
+    }
+
+    fn save(&self, schedule: &Schedule) {
+        if let Some(db) = &self.store {
+            let res = match serde_json::to_vec(schedule) {
+                Ok(value) => db
+                    .put(schedule.id.as_bytes(), value)
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = res {
+                eprintln!("Error saving schedule {}: {e}", schedule.id);
+            }
+        }
+    }
+
+    pub fn all(&self) -> Vec<Schedule> {
+        self.schedules.iter().map(|s| s.clone()).collect()
+    }
+
+    pub fn create(
+        &self,
+        user: Address,
+        spec: ScheduleSpec,
+        action: ActionSpec,
+    ) -> Result<Schedule, SchedulerError> {
+        spec.validate()?;
+        let now = now_ms();
+        let schedule = Schedule {
+            id: format!("sched-{:016x}", rand::random::<u64>()),
+            user,
+            next_run: spec.next_after(now).unwrap_or_default(),
+            spec,
+            action,
+            paused: false,
+            paused_reason: String::new(),
+            last_run: None,
+            runs: 0,
+        };
+        self.save(&schedule);
+        self.schedules.insert(schedule.id.clone(), schedule.clone());
+        Ok(schedule)
+    }
+
+    /// Pauses or resumes a schedule, resuming plans the next run from now
+    pub fn set_paused(
+        &self,
+        user: Address,
+        id: &str,
+        paused: bool,
+    ) -> Result<Schedule, SchedulerError> {
+        let mut schedule = match self.schedules.get_mut(id) {
+            Some(schedule) if schedule.user == user => schedule,
+            _ => return Err(SchedulerError::NotFound),
+        };
+        if schedule.paused != paused {
+            schedule.paused = paused;
+            schedule.paused_reason.clear();
+            schedule.next_run = match paused {
+                true => 0,
+                false => schedule.spec.next_after(now_ms()).unwrap_or_default(),
+            };
+            self.save(&schedule);
+        }
+        Ok(schedule.clone())
+    }
+
+    pub fn remove(&self, user: Address, id: &str) -> Option<Schedule> {
+        let (_, schedule) = self.schedules.remove_if(id, |_, s| s.user == user)?;
+        if let Some(db) = &self.store {
+            if let Err(e) = db.delete(id.as_bytes()) {
+                eprintln!("Error deleting schedule {id}: {e}");
+            }
+        }
+        Some(schedule)
+    }
+
+    /// Schedules due at `now`, already moved on to their next run
+    pub fn due(&self, now: u64) -> Vec<Schedule> {
+        let mut fired = Vec::new();
+        for mut schedule in self.schedules.iter_mut() {
+            if schedule.paused || schedule.next_run == 0 || schedule.next_run > now {
+                continue;
+            }
+            let fired_run = schedule.clone();
+            schedule.last_run = Some(now);
+            schedule.runs += 1;
+            schedule.next_run = schedule.spec.next_after(now).unwrap_or_default();
+            self.save(&schedule);
+            fired.push(fired_run);
+        }
+        fired
+    }
+}
+
+/// Parsed five field cron expression: minute, hour, day of month, month, day of week.
+/// Fields accept `*`, numbers, `a-b` ranges, `/step` and comma lists, plus the usual `@hourly` style macros.
+#[derive(Clone, Debug)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronExpr {
+    pub fn parse(expr: &str) -> Result<Self, SchedulerError> {
+        let expr = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(SchedulerError::InvalidCron(format!(
+                "expected 5 fields, got {}",
+                fields.len()
+            )));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // 7 is another name for sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(CronExpr {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            // like cron, `*/2` still counts as unrestricted for the day rule
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// First matching minute strictly after `after`, unix millis
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let mut t = (after / MINUTE_MS + 1) * MINUTE_MS;
+        let limit = t + CRON_SEARCH_DAYS as u64 * DAY_MS;
+        while t < limit {
+            let days = (t / DAY_MS) as i64;
+            let (_, month, day) = civil_from_days(days);
+            let weekday = (days + 4).rem_euclid(7) as u32;
+            let (day_ok, weekday_ok) = (bit(self.days, day), bit(self.weekdays, weekday));
+            // cron matches either field when both are restricted, otherwise both
+            let day_matches = match self.any_day || self.any_weekday {
+                true => day_ok && weekday_ok,
+                false => day_ok || weekday_ok,
+            };
+            if !bit(self.months, month) || !day_matches {
+                t = (days as u64 + 1) * DAY_MS;
+                continue;
+            }
+            let minute_of_day = (t % DAY_MS) / MINUTE_MS;
+            let (hour, minute) = ((minute_of_day / 60) as u32, (minute_of_day % 60) as u32);
+            if !bit(self.hours, hour) {
+                t = (t / (60 * MINUTE_MS) + 1) * 60 * MINUTE_MS;
+                continue;
+            }
+            if !bit(self.minutes, minute) {
+                t += MINUTE_MS;
+                continue;
+            }
+            return Some(t);
+        }
+        None
+    }
+}
+
+/// A schedule loaded from the store can't run until its user resumes it, see `RESTORED_REASON`
+fn restore(mut schedule: Schedule) -> Schedule {
+    schedule.paused = true;
+    schedule.next_run = 0;
+    schedule.paused_reason = RESTORED_REASON.to_string();
+    schedule
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, SchedulerError> {
+    let invalid = || SchedulerError::InvalidCron(format!("bad field {field}"));
+    let mut set = 0u64;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (item, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((a, b)) => (
+                    a.parse::<u32>().map_err(|_| invalid())?,
+                    b.parse::<u32>().map_err(|_| invalid())?,
+                ),
+                // `5/15` runs from 5 to the end of the range
+                None => {
+                    let start = range.parse::<u32>().map_err(|_| invalid())?;
+                    (start, if item.contains('/') { max } else { start })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+/// Days since 1970-01-01 to (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01, a monday
+    const JAN_1_2024: i64 = 19_723;
+
+    fn bits(ns: &[u32]) -> u64 {
+        ns.iter().fold(0, |set, n| set | 1 << n)
+    }
+
+    fn day_ms(days: i64) -> u64 {
+        days as u64 * DAY_MS
+    }
+
+    #[test]
+    fn parses_cron_fields() {
+        assert_eq!(parse_field("*/15", 0, 59).unwrap(), bits(&[0, 15, 30, 45]));
+        assert_eq!(parse_field("1-5", 0, 7).unwrap(), bits(&[1, 2, 3, 4, 5]));
+        assert_eq!(parse_field("5/20", 0, 59).unwrap(), bits(&[5, 25, 45]));
+        assert_eq!(
+            parse_field("1,3-4,10-20/5", 0, 59).unwrap(),
+            bits(&[1, 3, 4, 10, 15, 20])
+        );
+        for bad in ["60", "0/0", "5-1", "x", "1-", ""] {
+            assert!(parse_field(bad, 0, 59).is_err(), "{bad}");
+        }
+        assert!(parse_field("0", 1, 31).is_err());
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either() {
+        // the 13th or any friday, january 5th is the first friday
+        let cron = CronExpr::parse("0 0 13 * 5").unwrap();
+        assert_eq!(
+            cron.next_after(day_ms(JAN_1_2024)),
+            Some(day_ms(JAN_1_2024 + 4))
+        );
+        let cron = CronExpr::parse("0 0 13 * *").unwrap();
+        assert_eq!(
+            cron.next_after(day_ms(JAN_1_2024)),
+            Some(day_ms(JAN_1_2024 + 12))
+        );
+    }
+
+    #[test]
+    fn stepped_star_day_field_counts_as_unrestricted() {
+        // mondays on odd days, the 8th is even so the 15th is the first
+        let cron = CronExpr::parse("0 0 */2 * 1").unwrap();
+        assert_eq!(
+            cron.next_after(day_ms(JAN_1_2024)),
+            Some(day_ms(JAN_1_2024 + 14))
+        );
+        let cron = CronExpr::parse("30 12 1 */3 *").unwrap();
+        let april = day_ms(JAN_1_2024 + 91) + 12 * 60 * MINUTE_MS + 30 * MINUTE_MS;
+        assert_eq!(cron.next_after(day_ms(JAN_1_2024 + 1)), Some(april));
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(JAN_1_2024), (2024, 1, 1));
+        assert_eq!(civil_from_days(JAN_1_2024 + 59), (2024, 2, 29));
+        assert_eq!(civil_from_days(JAN_1_2024 + 60), (2024, 3, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn restored_schedules_are_paused_with_a_reason() {
+        let spec = ScheduleSpec::Interval { secs: 60 };
+        let schedule = Schedule {
+            id: "sched".to_string(),
+            user: Address::ZERO,
+            next_run: spec.next_after(0).unwrap(),
+            spec,
+            action: ActionSpec {
+                chainid: 1,
+                target_address: String::new(),
+                abi: String::new(),
+                function_name: String::new(),
+                params: Vec::new(),
+                calls: Vec::new(),
+                guards: Vec::new(),
+                gas: Default::default(),
+            },
+            paused: false,
+            paused_reason: String::new(),
+            last_run: None,
+            runs: 0,
+        };
+        let restored = restore(schedule);
+        assert!(restored.paused);
+        assert_eq!(restored.next_run, 0);
+        assert!(!restored.paused_reason.is_empty());
+
+        let scheduler = Scheduler::default();
+        scheduler.schedules.insert(restored.id.clone(), restored);
+        let resumed = scheduler.set_paused(Address::ZERO, "sched", false).unwrap();
+        assert!(!resumed.paused && resumed.paused_reason.is_empty());
+        assert!(resumed.next_run > 0);
+    }
+}
@@ -0,0 +1,597 @@
+use alloy::primitives::hex;
+use alloy::transports::http::reqwest;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error("Invalid sink config: {0}")]
+    InvalidConfig(String),
+    #[error("Connection error: {0}")]
+    Connection(String),
+    #[error("Destination responded with status {0}")]
+    Status(u16),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Endpoint did not echo the challenge")]
+    ChallengeFailed,
+}
+
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ownership verification state of a sink
+#[derive(Clone, Debug)]
+pub enum VerificationStatus {
+    Pending,
+    Verified,
+    Failed(String),
+}
+
+/// Destination for matched events
+#[tonic::async_trait]
+pub trait EventSink: Send + Sync {
+    /// Human readable destination, used in logs and delivery records
+    fn destination(&self) -> String;
+
+    /// Sends the payload, returns the response status for HTTP sinks
+    async fn deliver(&self, payload: &Value) -> Result<Option<u16>, SinkError>;
+
+    /// Whether the destination has to prove ownership before it receives deliveries
+    fn needs_verification(&self) -> bool {
+        false
+    }
+
+    /// Runs the ownership handshake
+    async fn verify(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// Sink configuration as given at Subscribe time
+#[derive(Clone, Debug)]
+pub enum SinkSpec {
+    Webhook {
+        url: String,
+        headers: Vec<(String, String)>,
+    },
+    Nats {
+        url: String,
+        subject: String,
+    },
+    RedisStream {
+        url: String,
+        stream: String,
+    },
+}
+
+impl SinkSpec {
+    pub fn build(self) -> Result<Arc<dyn EventSink>, SinkError> {
+        match self {
+            SinkSpec::Webhook { url, headers } => {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(SinkError::InvalidConfig(format!(
+                        "invalid webhook url {url}"
+                    )));
+                }
+                Ok(Arc::new(WebhookSink {
+                    url,
+                    headers,
+                    client: reqwest::Client::new(),
+                }))
+            }
+            SinkSpec::Nats { url, subject } => {
+                if subject.is_empty() || subject.contains(char::is_whitespace) {
+                    return Err(SinkError::InvalidConfig(format!(
+                        "invalid NATS subject \"{subject}\""
+                    )));
+                }
+                let (addr, credentials) = host_port(&url, "nats://", 4222)?;
+                Ok(Arc::new(NatsSink {
+                    addr,
+                    credentials,
+                    subject,
+                    conn: Mutex::new(None),
+                }))
+            }
+            SinkSpec::RedisStream { url, stream } => {
+                if stream.is_empty() {
+                    return Err(SinkError::InvalidConfig(
+                        "empty Redis stream key".to_string(),
+                    ));
+                }
+                let (addr, credentials) = host_port(&url, "redis://", 6379)?;
+                Ok(Arc::new(RedisStreamSink {
+                    addr,
+                    credentials,
+                    stream,
+                    conn: Mutex::new(None),
+                }))
+            }
+        }
+    }
+}
+
+/// Userinfo of a sink url, `user:password` or a lone password or token
+#[derive(Clone, Debug, PartialEq)]
+struct Credentials {
+    user: Option<String>,
+    password: String,
+}
+
+/// Splits `scheme://[[user]:password@]host[:port]` into the socket address and the credentials
+fn host_port(
+    url: &str,
+    scheme: &str,
+    default_port: u16,
+) -> Result<(String, Option<Credentials>), SinkError> {
+    let rest = url.strip_prefix(scheme).unwrap_or(url);
+    let rest = rest.trim_end_matches('/');
+    let (auth, host) = match rest.rsplit_once('@') {
+        Some((auth, host)) => (Some(auth), host),
+        None => (None, rest),
+    };
+    if host.is_empty() || host.contains('/') {
+        return Err(SinkError::InvalidConfig(format!("invalid url {url}")));
+    }
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:{default_port}")
+    };
+    let credentials = auth.map(|a| match a.split_once(':') {
+        Some((user, password)) => Credentials {
+            user: (!user.is_empty()).then(|| user.to_string()),
+            password: password.to_string(),
+        },
+        None => Credentials {
+            user: None,
+            password: a.to_string(),
+        },
+    });
+    Ok((addr, credentials))
+}
+
+async fn connect(addr: &str) -> Result<BufReader<TcpStream>, SinkError> {
+    match TcpStream::connect(addr).await {
+        Ok(stream) => Ok(BufReader::new(stream)),
+        Err(e) => Err(SinkError::Connection(e.to_string())),
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(conn: &mut R) -> Result<String, SinkError> {
+    let mut line = String::new();
+    match conn.read_line(&mut line).await {
+        Ok(0) => Err(SinkError::Connection("connection closed".to_string())),
+        Ok(_) => Ok(line.trim_end().to_string()),
+        Err(e) => Err(SinkError::Connection(e.to_string())),
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(conn: &mut W, buf: &[u8]) -> Result<(), SinkError> {
+    conn.write_all(buf)
+        .await
+        .map_err(|e| SinkError::Connection(e.to_string()))
+}
+
+/// Posts the payload as JSON to an HTTP endpoint
+pub struct WebhookSink {
+    url: String,
+    headers: Vec<(String, String)>,
+    client: reqwest::Client,
+}
+
+#[tonic::async_trait]
+impl EventSink for WebhookSink {
+    fn destination(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn deliver(&self, payload: &Value) -> Result<Option<u16>, SinkError> {
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header("content-type", "application/json")
+            .body(payload.to_string());
+        for (k, v) in &self.headers {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = match req.send().await {
+            Ok(res) => res,
+            Err(e) => return Err(SinkError::Connection(e.to_string())),
+        };
+        if !res.status().is_success() {
+            return Err(SinkError::Status(res.status().as_u16()));
+        }
+        Ok(Some(res.status().as_u16()))
+    }
+
+    fn needs_verification(&self) -> bool {
+        true
+    }
+
+    /// Posts a random challenge, the endpoint must answer 2xx with the challenge
+    /// either as the raw body or as `{"challenge": "..."}`
+    async fn verify(&self) -> Result<(), SinkError> {
+        let challenge = hex::encode(rand::random::<[u8; 16]>());
+        let body = json!({
+            "type": "chainhooks.challenge",
+            "challenge": challenge,
+        });
+        let mut req = self
+            .client
+            .post(&self.url)
+            .timeout(CHALLENGE_TIMEOUT)
+            .header("content-type", "application/json")
+            .body(body.to_string());
+        for (k, v) in &self.headers {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = match req.send().await {
+            Ok(res) => res,
+            Err(e) => return Err(SinkError::Connection(e.to_string())),
+        };
+        if !res.status().is_success() {
+            return Err(SinkError::Status(res.status().as_u16()));
+        }
+        let text = match res.text().await {
+            Ok(text) => text,
+            Err(e) => return Err(SinkError::Connection(e.to_string())),
+        };
+        let echoed = match serde_json::from_str::<Value>(&text) {
+            Ok(Value::Object(map)) => {
+                map.get("challenge").and_then(|c| c.as_str()) == Some(&challenge)
+            }
+            _ => text.trim() == challenge,
+        };
+        if echoed {
+            Ok(())
+        } else {
+            Err(SinkError::ChallengeFailed)
+        }
+    }
+}
+
+/// Publishes the payload on a NATS subject using the plain text client protocol
+pub struct NatsSink {
+    addr: String,
+    credentials: Option<Credentials>,
+    subject: String,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl NatsSink {
+    async fn open(&self) -> Result<BufReader<TcpStream>, SinkError> {
+        let mut conn = connect(&self.addr).await?;
+        let info = read_line(&mut conn).await?;
+        if !info.starts_with("INFO") {
+            return Err(SinkError::Protocol(format!("unexpected greeting {info}")));
+        }
+        write_all(
+            &mut conn,
+            Self::connect_frame(self.credentials.as_ref()).as_bytes(),
+        )
+        .await?;
+        Ok(conn)
+    }
+
+    /// CONNECT with user and password, or the lone password as auth token
+    fn connect_frame(credentials: Option<&Credentials>) -> String {
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "chainhooks",
+        });
+        match credentials {
+            Some(Credentials {
+                user: Some(user),
+                password,
+            }) => {
+                options["user"] = json!(user);
+                options["pass"] = json!(password);
+            }
+            Some(Credentials {
+                user: None,
+                password,
+            }) => options["auth_token"] = json!(password),
+            None => {}
+        }
+        format!("CONNECT {options}\r\n")
+    }
+
+    /// PUB followed by a PING, the size is the body's byte length
+    fn pub_frame(subject: &str, body: &str) -> String {
+        format!("PUB {} {}\r\n{}\r\nPING\r\n", subject, body.len(), body)
+    }
+
+    async fn publish<C: AsyncBufRead + AsyncWrite + Unpin>(
+        conn: &mut C,
+        subject: &str,
+        body: &str,
+    ) -> Result<(), SinkError> {
+        write_all(conn, Self::pub_frame(subject, body).as_bytes()).await?;
+        // the server answers PING in order, so PONG means the publish was accepted
+        loop {
+            let line = read_line(conn).await?;
+            if line == "PONG" {
+                return Ok(());
+            }
+            if line == "PING" {
+                write_all(conn, b"PONG\r\n").await?;
+                continue;
+            }
+            if line.starts_with("-ERR") {
+                return Err(SinkError::Protocol(line));
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl EventSink for NatsSink {
+    fn destination(&self) -> String {
+        format!("nats://{}/{}", self.addr, self.subject)
+    }
+
+    async fn deliver(&self, payload: &Value) -> Result<Option<u16>, SinkError> {
+        let mut guard = self.conn.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.open().await?);
+        }
+        let conn = guard.as_mut().unwrap();
+        let res = Self::publish(conn, &self.subject, &payload.to_string()).await;
+        if res.is_err() {
+            // drop the connection, the next delivery reconnects
+            *guard = None;
+        }
+        res.map(|_| None)
+    }
+}
+
+/// Appends the payload to a Redis stream with XADD
+pub struct RedisStreamSink {
+    addr: String,
+    credentials: Option<Credentials>,
+    stream: String,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisStreamSink {
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        buf.into_bytes()
+    }
+
+    /// Reads one RESP reply, only the reply kinds AUTH and XADD produce are handled
+    async fn reply<R: AsyncBufRead + Unpin>(conn: &mut R) -> Result<String, SinkError> {
+        let line = read_line(conn).await?;
+        match line.chars().next() {
+            Some('+') | Some(':') => Ok(line[1..].to_string()),
+            Some('-') => Err(SinkError::Protocol(line[1..].to_string())),
+            Some('$') => {
+                let len = match line[1..].parse::<i64>() {
+                    Ok(len) if len < 0 => {
+                        return Err(SinkError::Protocol("nil reply".to_string()));
+                    }
+                    Ok(len) => len as usize,
+                    Err(_) => return Err(SinkError::Protocol(format!("invalid reply {line}"))),
+                };
+                // the bulk string is length prefixed and may itself contain line breaks
+                let mut buf = vec![0; len + 2];
+                if let Err(e) = conn.read_exact(&mut buf).await {
+                    return Err(SinkError::Connection(e.to_string()));
+                }
+                buf.truncate(len);
+                String::from_utf8(buf).map_err(|_| SinkError::Protocol("invalid reply".to_string()))
+            }
+            _ => Err(SinkError::Protocol(format!("unexpected reply {line}"))),
+        }
+    }
+
+    /// AUTH with the ACL user when the url names one, the default user otherwise
+    fn auth_command(credentials: &Credentials) -> Vec<u8> {
+        match &credentials.user {
+            Some(user) => Self::command(&["AUTH", user, &credentials.password]),
+            None => Self::command(&["AUTH", &credentials.password]),
+        }
+    }
+
+    async fn open(&self) -> Result<BufReader<TcpStream>, SinkError> {
+        let mut conn = connect(&self.addr).await?;
+        if let Some(credentials) = &self.credentials {
+            write_all(&mut conn, &Self::auth_command(credentials)).await?;
+            Self::reply(&mut conn).await?;
+        }
+        Ok(conn)
+    }
+}
+
+#[tonic::async_trait]
+impl EventSink for RedisStreamSink {
+    fn destination(&self) -> String {
+        format!("redis://{}/{}", self.addr, self.stream)
+    }
+
+    async fn deliver(&self, payload: &Value) -> Result<Option<u16>, SinkError> {
+        let mut guard = self.conn.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.open().await?);
+        }
+        let conn = guard.as_mut().unwrap();
+        let body = payload.to_string();
+        let cmd = Self::command(&["XADD", &self.stream, "*", "payload", &body]);
+        let mut res = write_all(conn, &cmd).await;
+        if res.is_ok() {
+            res = Self::reply(conn).await.map(|_| ());
+        }
+        if res.is_err() {
+            *guard = None;
+        }
+        res.map(|_| None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(user: Option<&str>, password: &str) -> Option<Credentials> {
+        Some(Credentials {
+            user: user.map(str::to_string),
+            password: password.to_string(),
+        })
+    }
+
+    #[test]
+    fn host_port_reads_credentials() {
+        let parse = |url| host_port(url, "redis://", 6379).unwrap();
+        assert_eq!(
+            parse("redis://localhost"),
+            ("localhost:6379".to_string(), None)
+        );
+        assert_eq!(
+            parse("redis://alice:secret@db:6380/"),
+            ("db:6380".to_string(), credentials(Some("alice"), "secret"))
+        );
+        assert_eq!(
+            parse("redis://:secret@db"),
+            ("db:6379".to_string(), credentials(None, "secret"))
+        );
+        assert_eq!(
+            parse("redis://token@db"),
+            ("db:6379".to_string(), credentials(None, "token"))
+        );
+        assert!(host_port("redis://db/0/x", "redis://", 6379).is_err());
+    }
+
+    #[test]
+    fn redis_auth_sends_acl_user() {
+        let with_user = credentials(Some("alice"), "secret").unwrap();
+        assert_eq!(
+            RedisStreamSink::auth_command(&with_user),
+            b"*3\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$6\r\nsecret\r\n"
+        );
+        let password_only = credentials(None, "secret").unwrap();
+        assert_eq!(
+            RedisStreamSink::auth_command(&password_only),
+            b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n"
+        );
+    }
+
+    #[test]
+    fn redis_command_counts_bytes() {
+        assert_eq!(
+            RedisStreamSink::command(&["XADD", "s", "*", "payload", "é"]),
+            b"*5\r\n$4\r\nXADD\r\n$1\r\ns\r\n$1\r\n*\r\n$7\r\npayload\r\n$2\r\n\xc3\xa9\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn redis_reply_kinds() {
+        let reply = |bytes: &'static [u8]| async move {
+            let mut conn = bytes;
+            RedisStreamSink::reply(&mut conn).await
+        };
+        assert_eq!(reply(b"+OK\r\n").await.unwrap(), "OK");
+        assert_eq!(reply(b":1\r\n").await.unwrap(), "1");
+        assert_eq!(
+            reply(b"$15\r\n1700000000000-0\r\n").await.unwrap(),
+            "1700000000000-0"
+        );
+        assert_eq!(reply(b"$4\r\na\r\nb\r\n").await.unwrap(), "a\r\nb");
+        assert!(matches!(
+            reply(b"-WRONGPASS invalid username-password pair\r\n").await,
+            Err(SinkError::Protocol(e)) if e.starts_with("WRONGPASS")
+        ));
+        assert!(matches!(
+            reply(b"$-1\r\n").await,
+            Err(SinkError::Protocol(_))
+        ));
+        assert!(matches!(
+            reply(b"*1\r\n").await,
+            Err(SinkError::Protocol(_))
+        ));
+        assert!(matches!(reply(b"").await, Err(SinkError::Connection(_))));
+    }
+
+    #[test]
+    fn nats_frames() {
+        assert_eq!(
+            NatsSink::pub_frame("chain.events", "{\"a\":\"é\"}"),
+            "PUB chain.events 10\r\n{\"a\":\"é\"}\r\nPING\r\n"
+        );
+        let connect = |credentials: Option<Credentials>| {
+            let frame = NatsSink::connect_frame(credentials.as_ref());
+            let json = frame
+                .strip_prefix("CONNECT ")
+                .and_then(|f| f.strip_suffix("\r\n"))
+                .unwrap();
+            serde_json::from_str::<Value>(json).unwrap()
+        };
+        let options = connect(credentials(Some("alice"), "secret"));
+        assert_eq!(options["user"], "alice");
+        assert_eq!(options["pass"], "secret");
+        assert_eq!(connect(credentials(None, "tok"))["auth_token"], "tok");
+        assert!(connect(None).get("user").is_none());
+    }
+
+    #[tokio::test]
+    async fn nats_publish_waits_for_pong() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = BufReader::new(client);
+        server.write_all(b"PING\r\nPONG\r\n").await.unwrap();
+        NatsSink::publish(&mut client, "s", "hi").await.unwrap();
+        drop(client);
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(sent, "PUB s 2\r\nhi\r\nPING\r\nPONG\r\n");
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = BufReader::new(client);
+        server
+            .write_all(b"-ERR 'Permissions Violation'\r\n")
+            .await
+            .unwrap();
+        let res = NatsSink::publish(&mut client, "s", "hi").await;
+        assert!(matches!(res, Err(SinkError::Protocol(_))));
+    }
+
+    /// Needs a local server, `cargo test -- --ignored` with REDIS_TEST_URL to override the address
+    #[tokio::test]
+    #[ignore]
+    async fn redis_server_delivery() {
+        let url = std::env::var("REDIS_TEST_URL").unwrap_or("redis://127.0.0.1:6379".to_string());
+        let sink = SinkSpec::RedisStream {
+            url,
+            stream: "chainhooks-test".to_string(),
+        }
+        .build()
+        .unwrap();
+        sink.deliver(&json!({"n": 1})).await.unwrap();
+        sink.deliver(&json!({"n": 2})).await.unwrap();
+    }
+
+    /// Needs a local server, `cargo test -- --ignored` with NATS_TEST_URL to override the address
+    #[tokio::test]
+    #[ignore]
+    async fn nats_server_delivery() {
+        let url = std::env::var("NATS_TEST_URL").unwrap_or("nats://127.0.0.1:4222".to_string());
+        let sink = SinkSpec::Nats {
+            url,
+            subject: "chainhooks.test".to_string(),
+        }
+        .build()
+        .unwrap();
+        sink.deliver(&json!({"n": 1})).await.unwrap();
+        sink.deliver(&json!({"n": 2})).await.unwrap();
+    }
+}
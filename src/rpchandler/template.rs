@@ -0,0 +1,213 @@
+use crate::rpchandler::eventTypes::{DecodedLog, sol_value_to_json};
+use crate::rpchandler::rpc_types::RelayerTxResult;
+use crate::transactionTypes::RelayerError;
+use alloy::json_abi::Event;
+use serde_json::{Map, Value, json};
+
+const LOG_FIELDS: [&str; 6] = [
+    "address",
+    "blockNumber",
+    "blockHash",
+    "txHash",
+    "logIndex",
+    "removed",
+];
+const BLOCK_FIELDS: [&str; 4] = ["number", "hash", "timestamp", "baseFee"];
+const TRANSACTION_FIELDS: [&str; 5] = ["from", "to", "value", "selector", "input"];
+const TX_FIELDS: [&str; 3] = ["hash", "status", "error"];
+
+/// User supplied JSON body for deliveries.
+/// String values may contain `{{path}}` placeholders, a string made of a single placeholder
+/// is replaced by the typed value, otherwise the value is interpolated as text.
+#[derive(Clone, Debug)]
+pub struct PayloadTemplate {
+    body: Value,
+    uses_tx: bool,
+}
+
+/// Values available to a template when it is rendered
+pub struct TemplateContext<'a> {
+    pub log: &'a DecodedLog,
+    pub tx: Option<&'a RelayerTxResult>,
+}
+
+impl PayloadTemplate {
+    /// Parses and validates a template against the subscription's event ABI
+    pub fn parse(src: &str, event: Option<&Event>) -> Result<Self, RelayerError> {
+        let body: Value = match serde_json::from_str(src) {
+            Ok(body) => body,
+            Err(e) => return Err(RelayerError::InvalidTemplate(e.to_string())),
+        };
+        let mut paths = Vec::new();
+        collect_paths(&body, &mut paths)?;
+        let mut uses_tx = false;
+        for path in &paths {
+            validate_path(path, event)?;
+            uses_tx |= path.starts_with("tx.");
+        }
+        Ok(PayloadTemplate { body, uses_tx })
+    }
+
+    /// True when the template reads relayer transaction results,
+    /// such templates are rendered once the relayer transaction settles
+    pub fn uses_tx(&self) -> bool {
+        self.uses_tx
+    }
+
+    pub fn render(&self, ctx: &TemplateContext) -> Value {
+        render_value(&self.body, ctx)
+    }
+}
+
+fn placeholders(s: &str) -> Result<Vec<(usize, usize, String)>, RelayerError> {
+    let mut found = Vec::new();
+    let mut rest = 0;
+    while let Some(start) = s[rest..].find("{{") {
+        let start = rest + start;
+        let end = match s[start..].find("}}") {
+            Some(end) => start + end + 2,
+            None => {
+                return Err(RelayerError::InvalidTemplate(format!(
+                    "unclosed placeholder in \"{s}\""
+                )));
+            }
+        };
+        found.push((start, end, s[start + 2..end - 2].trim().to_string()));
+        rest = end;
+    }
+    Ok(found)
+}
+
+fn collect_paths(value: &Value, paths: &mut Vec<String>) -> Result<(), RelayerError> {
+    match value {
+        Value::String(s) => {
+            for (_, _, path) in placeholders(s)? {
+                paths.push(path);
+            }
+        }
+        Value::Array(values) => {
+            for v in values {
+                collect_paths(v, paths)?;
+            }
+        }
+        Value::Object(map) => {
+            for v in map.values() {
+                collect_paths(v, paths)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn validate_path(path: &str, event: Option<&Event>) -> Result<(), RelayerError> {
+    let unknown = || RelayerError::InvalidTemplate(format!("unknown placeholder {{{{{path}}}}}"));
+    let (root, field) = path.split_once('.').ok_or_else(unknown)?;
+    let known =
+        match root {
+            "subscription" => field == "id",
+            "log" => LOG_FIELDS.contains(&field),
+            "block" => BLOCK_FIELDS.contains(&field),
+            "transaction" => TRANSACTION_FIELDS.contains(&field),
+            "tx" => TX_FIELDS.contains(&field),
+            "event" => match field {
+                "name" | "args" => true,
+                arg => match event {
+                    Some(event) => event.inputs.iter().enumerate().any(|(i, p)| {
+                        p.name == arg || (p.name.is_empty() && arg == format!("arg{i}"))
+                    }),
+                    None => false,
+                },
+            },
+            _ => false,
+        };
+    if known { Ok(()) } else { Err(unknown()) }
+}
+
+fn lookup(path: &str, ctx: &TemplateContext) -> Value {
+    let log = ctx.log;
+    match path.split_once('.').unwrap_or((path, "")) {
+        ("subscription", "id") => json!(log.sub_id),
+        ("event", "name") => json!(log.event_name),
+        ("event", "args") => {
+            let mut args = Map::new();
+            for arg in &log.args {
+                args.insert(arg.name.clone(), sol_value_to_json(&arg.value));
+            }
+            Value::Object(args)
+        }
+        ("event", arg) => log
+            .arg(arg)
+            .map(|a| sol_value_to_json(&a.value))
+            .unwrap_or(Value::Null),
+        ("log", "address") => json!(log.address.to_string()),
+        ("log", "blockNumber") => json!(log.block_number),
+        ("log", "blockHash") => json!(log.block_hash.map(|h| h.to_string())),
+        ("log", "txHash") => json!(log.tx_hash.map(|h| h.to_string())),
+        ("log", "logIndex") => json!(log.log_index),
+        ("log", "removed") => json!(log.removed),
+        ("block", field) => match &log.block {
+            Some(block) => match field {
+                "number" => json!(block.number),
+                "hash" => json!(block.hash.to_string()),
+                "timestamp" => json!(block.timestamp),
+                "baseFee" => json!(block.base_fee),
+                _ => Value::Null,
+            },
+            None => Value::Null,
+        },
+        ("transaction", field) => match &log.transaction {
+            Some(tx) => match field {
+                "from" => json!(tx.from.to_string()),
+                "to" => json!(tx.to.map(|a| a.to_string())),
+                "value" => json!(tx.value.to_string()),
+                "selector" => json!(tx.selector.map(|s| s.to_string())),
+                "input" => json!(tx.input.to_string()),
+                _ => Value::Null,
+            },
+            None => Value::Null,
+        },
+        ("tx", field) => match ctx.tx {
+            Some(tx) => match field {
+                "hash" => json!(tx.hash),
+                "status" => json!(tx.success),
+                "error" => json!(tx.error),
+                _ => Value::Null,
+            },
+            None => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+fn render_value(value: &Value, ctx: &TemplateContext) -> Value {
+    match value {
+        Value::String(s) => {
+            // validated at parse time, so placeholders can't fail here
+            let found = placeholders(s).unwrap_or_default();
+            if found.len() == 1 && found[0].0 == 0 && found[0].1 == s.len() {
+                return lookup(&found[0].2, ctx);
+            }
+            let mut out = String::new();
+            let mut last = 0;
+            for (start, end, path) in found {
+                out.push_str(&s[last..start]);
+                match lookup(&path, ctx) {
+                    Value::String(v) => out.push_str(&v),
+                    Value::Null => {}
+                    v => out.push_str(&v.to_string()),
+                }
+                last = end;
+            }
+            out.push_str(&s[last..]);
+            Value::String(out)
+        }
+        Value::Array(values) => Value::Array(values.iter().map(|v| render_value(v, ctx)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render_value(v, ctx)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
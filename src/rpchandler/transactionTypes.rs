@@ -1,11 +1,20 @@
-use alloy::dyn_abi::{DynSolType, DynSolValue, JsonAbiExt};
-use alloy::json_abi::{Function, JsonAbi, Param};
+use crate::rpchandler::eventTypes::{DecodedArg, DecodedLog, sol_value_to_string};
+use crate::rpchandler::expr::{self, Expr, ExprType, ExprValue};
+use alloy::dyn_abi::{DynSolType, DynSolValue, FunctionExt, JsonAbiExt, Specifier};
+use alloy::eips::{BlockId, BlockNumberOrTag};
+use alloy::json_abi::{Event, Function, JsonAbi, Param};
 use alloy::network::TransactionBuilder;
-use alloy::primitives::{Address, Bytes, FixedBytes, address};
+use alloy::primitives::{Address, Bytes, FixedBytes, U256, address};
+use alloy::providers::Provider;
 use alloy::rpc::types::Log;
 use alloy::rpc::types::TransactionRequest;
+use alloy::sol_types::decode_revert_reason;
+use alloy::transports::{RpcError, TransportErrorKind};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_json::json;
+use std::collections::HashMap;
+use std::str::FromStr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -42,15 +51,315 @@ pub enum RelayerError {
 
     #[error("Already Registered")]
     AlreadyRegistered,
+
+    #[error("No relayer registered for this address")]
+    NotRegistered,
+
+    #[error("Invalid payload template: {0}")]
+    InvalidTemplate(String),
+
+    #[error("Invalid delivery mode: {0}")]
+    InvalidDeliveryMode(String),
+
+    #[error("No value for parameter {0}")]
+    MissingParamValue(String),
+
+    #[error("Invalid parameter {0}")]
+    InvalidParam(String),
+
+    #[error("Invalid guard {0}")]
+    InvalidGuard(String),
+
+    #[error("Guard not met: {0}")]
+    GuardNotMet(String),
+
+    #[error("Simulation failed: {0}")]
+    SimulationFailed(String),
+
+    #[error("Base fee {0} is above the limit {1}")]
+    BaseFeeTooHigh(u128, u128),
+}
+
+/// Trigger metadata parameters, `tx.hash` is the transaction that emitted the log
+const LOG_PARAMS: [&str; 6] = [
+    "log.address",
+    "log.blockNumber",
+    "log.blockHash",
+    "log.txHash",
+    "log.logIndex",
+    "tx.hash",
+];
+/// Read from the trigger's block header, fetched for log triggers that use one
+const BLOCK_PARAMS: [&str; 4] = ["number", "hash", "timestamp", "baseFee"];
+
+/// Guard variables read from the action's chain right before sending
+const RELAYER_BALANCE: &str = "relayer.balance";
+const CHAIN_BASE_FEE: &str = "chain.baseFee";
+
+/// Whether an action parameter reads the block header of its trigger
+pub fn uses_block(param: &str) -> bool {
+    param.starts_with("block.") || (param.starts_with('=') && param.contains("block."))
+}
+
+/// Action parameter that reads a decoded event input:
+/// `event.<name>`, `event.<position>` or `data<N>` for the N-th non-indexed input
+enum EventField {
+    Name(String),
+    Position(usize),
+    Data(usize),
+}
+
+impl EventField {
+    fn parse(param: &str) -> Option<Self> {
+        if let Some(field) = param.strip_prefix("event.") {
+            return Some(match field.parse::<usize>() {
+                Ok(position) => EventField::Position(position),
+                Err(_) => EventField::Name(field.to_string()),
+            });
+        }
+        let index = param.strip_prefix("data")?.parse().ok()?;
+        Some(EventField::Data(index))
+    }
+
+    fn lookup<'a>(&self, args: &'a [DecodedArg]) -> Option<&'a DecodedArg> {
+        match self {
+            EventField::Name(name) => args.iter().find(|a| a.name == *name),
+            EventField::Position(position) => args.iter().find(|a| a.position == *position),
+            EventField::Data(index) => args.iter().filter(|a| !a.indexed).nth(*index),
+        }
+    }
+}
+
+/// Checks at subscribe time that a metadata parameter exists and that an `event.*` or `dataN`
+/// parameter names an input of the subscription's event
+pub fn check_param(param: &str, event: Option<&Event>) -> Result<(), RelayerError> {
+    if param.starts_with("log.") || param.starts_with("tx.") {
+        return match LOG_PARAMS.contains(&param) {
+            true => Ok(()),
+            false => Err(RelayerError::InvalidParam(param.to_string())),
+        };
+    }
+    if let Some(field) = param.strip_prefix("block.") {
+        return match BLOCK_PARAMS.contains(&field) {
+            true => Ok(()),
+            false => Err(RelayerError::InvalidParam(param.to_string())),
+        };
+    }
+    let field = match EventField::parse(param) {
+        Some(field) => field,
+        None => return Ok(()),
+    };
+    let event = match event {
+        Some(event) => event,
+        None => {
+            return Err(RelayerError::InvalidParam(format!(
+                "{param}, the subscription has no event ABI"
+            )));
+        }
+    };
+    let found = match &field {
+        EventField::Name(name) => event.inputs.iter().any(|i| i.name == *name),
+        EventField::Position(position) => *position < event.inputs.len(),
+        EventField::Data(index) => event.inputs.iter().filter(|i| !i.indexed).count() > *index,
+    };
+    match found {
+        true => Ok(()),
+        false => Err(RelayerError::InvalidParam(format!(
+            "{param}, {} has no such input",
+            event.name
+        ))),
+    }
+}
+
+/// Type checks an `=` expression parameter against the function input it is passed to
+fn check_expr(
+    param: &str,
+    input: &Param,
+    types: Option<&HashMap<String, ExprType>>,
+) -> Result<(), RelayerError> {
+    let src = match param.strip_prefix('=') {
+        Some(src) => src,
+        None => return Ok(()),
+    };
+    let invalid = |e: expr::ExprError| RelayerError::InvalidParam(format!("{param}: {e}"));
+    let parsed = Expr::parse(src).map_err(invalid)?;
+    let ty = match types {
+        Some(types) => parsed.check(types).map_err(invalid)?,
+        None => return Ok(()),
+    };
+    let input_ty = input.resolve().map_err(|_| RelayerError::InvalidDataType)?;
+    if !expr::accepts(&input_ty, ty) {
+        return Err(RelayerError::InvalidParam(format!(
+            "{param} is {ty:?}, {} expects {}",
+            input.name, input.ty
+        )));
+    }
+    Ok(())
+}
+
+/// Value of a `log.*` or `tx.hash` parameter, `None` when the trigger doesn't carry it
+fn log_param(param: &str, trigger: &DecodedLog) -> Option<DynSolValue> {
+    match param {
+        "log.address" => Some(DynSolValue::Address(trigger.address)),
+        "log.blockNumber" => trigger
+            .block_number
+            .map(|n| DynSolValue::Uint(U256::from(n), 64)),
+        "log.blockHash" => trigger.block_hash.map(|h| DynSolValue::FixedBytes(h, 32)),
+        "log.txHash" | "tx.hash" => trigger.tx_hash.map(|h| DynSolValue::FixedBytes(h, 32)),
+        "log.logIndex" => trigger
+            .log_index
+            .map(|n| DynSolValue::Uint(U256::from(n), 64)),
+        _ => None,
+    }
 }
 
-#[derive(Clone, Default)]
+/// Converts a resolved parameter to the function input type.
+/// 32 byte words convert to addresses and integers, anything else is re-read from its text form
+/// so a `uint64` event value fits a `uint256` input and out of range values are rejected
+fn convert_value(value: DynSolValue, ty: &DynSolType) -> Result<DynSolValue, RelayerError> {
+    if ty.matches(&value) {
+        return Ok(value);
+    }
+    let word = match &value {
+        DynSolValue::FixedBytes(word, 32) => Some(*word),
+        DynSolValue::Address(addr) => Some(addr.into_word()),
+        _ => None,
+    };
+    if let Some(word) = word {
+        match ty {
+            DynSolType::Address if word[..12].iter().all(|b| *b == 0) => {
+                return Ok(DynSolValue::Address(Address::from_word(word)));
+            }
+            DynSolType::FixedBytes(32) => return Ok(DynSolValue::FixedBytes(word, 32)),
+            DynSolType::Uint(bits) => {
+                let n = U256::from_be_bytes(word.0);
+                if n.bit_len() <= *bits {
+                    return Ok(DynSolValue::Uint(n, *bits));
+                }
+            }
+            _ => {}
+        }
+    }
+    if let (DynSolType::Bytes, DynSolValue::FixedBytes(word, size)) = (ty, &value) {
+        return Ok(DynSolValue::Bytes(word[..*size].to_vec()));
+    }
+    ty.coerce_str(&sol_value_to_string(&value))
+        .map_err(|_| RelayerError::InvalidDataType)
+}
+
+/// View call whose decoded result feeds a `call:<n>` action parameter, run right before the action is encoded
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ViewCall {
+    /// contract address, or a parameter like `log.address` or `event.pool`
+    pub target: String,
+    /// `function debtOf(address) view returns (uint256)` or `debtOf(address)(uint256)`
+    pub function: String,
+    /// same syntax as action parameters
+    pub args: Vec<String>,
+    /// which return value is used
+    pub output: usize,
+}
+
+impl ViewCall {
+    fn parse_function(&self) -> Result<Function, RelayerError> {
+        let function = match Function::parse(self.function.trim()) {
+            Ok(function) => function,
+            Err(_) => {
+                return Err(RelayerError::InvalidParam(format!(
+                    "view function {}",
+                    self.function
+                )));
+            }
+        };
+        if self.output >= function.outputs.len() {
+            return Err(RelayerError::InvalidParam(format!(
+                "{} has no output {}",
+                function.name, self.output
+            )));
+        }
+        Ok(function)
+    }
+}
+
+/// Fee and gas limit overrides of an action, anything unset is estimated by the node. Amounts are wei
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GasPolicy {
+    pub max_fee_per_gas: Option<u128>,
+    pub max_priority_fee_per_gas: Option<u128>,
+    /// replaces the estimate
+    pub gas_limit: Option<u64>,
+    /// scales the estimate, ignored with `gas_limit`
+    pub gas_limit_multiplier: Option<f64>,
+    /// the action is deferred while the chain's base fee is above this
+    pub max_base_fee: Option<u128>,
+}
+
+impl GasPolicy {
+    /// Fails with `RelayerError::BaseFeeTooHigh` while the latest base fee is above `max_base_fee`
+    pub async fn check_base_fee<P: Provider>(
+        &self,
+        provider: &P,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let max = match self.max_base_fee {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let base_fee = provider
+            .get_block_by_number(BlockNumberOrTag::Latest)
+            .await?
+            .and_then(|block| block.header.base_fee_per_gas);
+        match base_fee {
+            Some(fee) if fee as u128 > max => {
+                Err(Box::new(RelayerError::BaseFeeTooHigh(fee as u128, max)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn gas_limit(&self, estimate: u64) -> u64 {
+        match (self.gas_limit, self.gas_limit_multiplier) {
+            (Some(limit), _) => limit,
+            (None, Some(multiplier)) => (estimate as f64 * multiplier).ceil() as u64,
+            (None, None) => estimate,
+        }
+    }
+
+    /// Sets the gas limit and fees, a fee left unset is estimated and the priority fee never exceeds the max fee
+    pub async fn apply<P: Provider>(
+        &self,
+        provider: &P,
+        tx: TransactionRequest,
+        estimate: u64,
+    ) -> Result<TransactionRequest, Box<dyn std::error::Error + Send + Sync>> {
+        let tx = tx.with_gas_limit(self.gas_limit(estimate));
+        if self.max_fee_per_gas.is_none() && self.max_priority_fee_per_gas.is_none() {
+            return Ok(tx);
+        }
+        let fees = provider.estimate_eip1559_fees().await?;
+        let max_fee = self.max_fee_per_gas.unwrap_or(fees.max_fee_per_gas);
+        let priority_fee = self
+            .max_priority_fee_per_gas
+            .unwrap_or(fees.max_priority_fee_per_gas)
+            .min(max_fee);
+        Ok(tx
+            .with_max_fee_per_gas(max_fee)
+            .with_max_priority_fee_per_gas(priority_fee))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct RawTransaction {
     pub chain_id: usize,
     pub contract_address: String,
     abi: String,
     function_name: String,
     params: Vec<(usize, String)>,
+    /// don't fire for events of a `from_block` backfill
+    pub skip_historical: bool,
+    pub calls: Vec<ViewCall>,
+    /// boolean expressions that must all hold, the action is skipped otherwise
+    pub guards: Vec<String>,
+    pub gas: GasPolicy,
 }
 
 impl RawTransaction {
@@ -67,53 +376,273 @@ impl RawTransaction {
             abi,
             function_name,
             params,
+            skip_historical: false,
+            calls: Vec::new(),
+            guards: Vec::new(),
+            gas: GasPolicy::default(),
+        }
+    }
+    fn function(&self) -> Result<Function, RelayerError> {
+        let abi: JsonAbi = match serde_json::from_str(&self.abi) {
+            Ok(abi) => abi,
+            Err(_) => return Err(RelayerError::InvalidABI),
+        };
+        match abi.function(&self.function_name).and_then(|f| f.first()) {
+            Some(function) => Ok(function.clone()),
+            None => Err(RelayerError::FunctionNotFound),
         }
     }
-    pub fn build_transaction(
-        self,
-        log: Log,
-    ) -> Result<TransactionRequest, Box<dyn std::error::Error + Send + Sync>> {
-        let contract_addr = Address::from_slice(self.contract_address.as_bytes());
-        let ABI: JsonAbi;
 
-        match serde_json::from_str(&self.abi) {
-            Ok(abi) => ABI = abi,
-            Err(e) => return Err(Box::new(RelayerError::InvalidABI)),
+    /// Checks the action when it is defined: the functions exist, the parameter counts match,
+    /// `call:<n>` parameters name a view call and `=` expressions type check against the function inputs.
+    /// `types` are the trigger's variables, `None` when they are only known once it fires
+    pub fn check(&self, types: Option<&HashMap<String, ExprType>>) -> Result<(), RelayerError> {
+        let function = self.function()?;
+        if self.params.len() != function.inputs.len() {
+            return Err(RelayerError::InvalidArgsCount);
+        }
+        for call in &self.calls {
+            let view = call.parse_function()?;
+            if call.args.len() != view.inputs.len() {
+                return Err(RelayerError::InvalidArgsCount);
+            }
+            for (arg, input) in call.args.iter().zip(&view.inputs) {
+                check_expr(arg, input, types)?;
+            }
+        }
+        for ((_, param), input) in self.params.iter().zip(&function.inputs) {
+            if let Some(index) = param.strip_prefix("call:") {
+                let call = match index.parse::<usize>().ok().and_then(|i| self.calls.get(i)) {
+                    Some(call) => call,
+                    None => return Err(RelayerError::InvalidParam(param.clone())),
+                };
+                let output = &call.parse_function()?.outputs[call.output];
+                let compatible = match (input.resolve(), output.resolve()) {
+                    (Ok(input_ty), Ok(output_ty)) => {
+                        input_ty == output_ty
+                            || expr::sol_type(&output_ty, false)
+                                .is_some_and(|ty| expr::accepts(&input_ty, ty))
+                    }
+                    _ => false,
+                };
+                if !compatible {
+                    return Err(RelayerError::InvalidParam(format!(
+                        "{} returns {}, {} expects {}",
+                        call.function, output.ty, input.name, input.ty
+                    )));
+                }
+                continue;
+            }
+            check_expr(param, input, types)?;
         }
+        let types = match types {
+            Some(types) => Some(self.guard_types(types)?),
+            None => None,
+        };
+        for guard in &self.guards {
+            let invalid = |e: expr::ExprError| RelayerError::InvalidGuard(format!("{guard}: {e}"));
+            match &types {
+                Some(types) => Expr::predicate(guard, types).map_err(invalid)?,
+                None => Expr::parse(guard).map_err(invalid)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// Guard variables, the parameter variables plus `call.<n>` view results,
+    /// `relayer.balance` and `chain.baseFee`
+    fn guard_types(
+        &self,
+        types: &HashMap<String, ExprType>,
+    ) -> Result<HashMap<String, ExprType>, RelayerError> {
+        let mut types = types.clone();
+        for (i, call) in self.calls.iter().enumerate() {
+            let output = &call.parse_function()?.outputs[call.output];
+            let ty = output
+                .resolve()
+                .ok()
+                .and_then(|t| expr::sol_type(&t, false));
+            if let Some(ty) = ty {
+                types.insert(format!("call.{i}"), ty);
+            }
+        }
+        types.insert(RELAYER_BALANCE.to_string(), ExprType::Uint);
+        types.insert(CHAIN_BASE_FEE.to_string(), ExprType::Uint);
+        Ok(types)
+    }
 
-        let function_val = ABI.function(&self.function_name).unwrap().first();
-        let function: Function;
+    /// Where the action is sent, used in delivery records
+    pub fn destination(&self) -> String {
+        format!("chain:{}/{}", self.chain_id, self.contract_address)
+    }
+
+    /// Reads the view calls and checks the guards, then encodes the call for a trigger.
+    /// A guard that doesn't hold fails with `RelayerError::GuardNotMet`
+    pub async fn prepare<P: Provider>(
+        &self,
+        provider: &P,
+        from: Address,
+        log: &Log,
+        trigger: &DecodedLog,
+    ) -> Result<TransactionRequest, Box<dyn std::error::Error + Send + Sync>> {
+        let views = self.read_views(provider, log, trigger).await?;
+        self.check_guards(provider, from, trigger, &views).await?;
+        self.build_transaction(log, trigger, &views)
+    }
 
-        match function_val {
-            Some(func) => {
-                function = func.clone();
+    /// Evaluates the guards, the relayer balance and base fee are only read when a guard uses them
+    async fn check_guards<P: Provider>(
+        &self,
+        provider: &P,
+        from: Address,
+        trigger: &DecodedLog,
+        views: &[DynSolValue],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.guards.is_empty() {
+            return Ok(());
+        }
+        let mut guards = Vec::new();
+        for guard in &self.guards {
+            guards.push(Expr::parse(guard)?);
+        }
+        let mut values = expr::param_values(trigger);
+        for (i, view) in views.iter().enumerate() {
+            if let Some(value) = ExprValue::from_sol(view) {
+                values.insert(format!("call.{i}"), value);
             }
-            None => return Err(Box::new(RelayerError::FunctionNotFound)),
         }
+        if guards.iter().any(|g| g.uses(RELAYER_BALANCE)) {
+            let balance = provider.get_balance(from).await?;
+            values.insert(RELAYER_BALANCE.to_string(), ExprValue::Uint(balance, 256));
+        }
+        if guards.iter().any(|g| g.uses(CHAIN_BASE_FEE)) {
+            let base_fee = provider
+                .get_block_by_number(BlockNumberOrTag::Latest)
+                .await?
+                .and_then(|block| block.header.base_fee_per_gas);
+            if let Some(fee) = base_fee {
+                values.insert(
+                    CHAIN_BASE_FEE.to_string(),
+                    ExprValue::Uint(U256::from(fee), 256),
+                );
+            }
+        }
+        for (guard, src) in guards.iter().zip(&self.guards) {
+            if !guard.matches(&values)? {
+                return Err(Box::new(RelayerError::GuardNotMet(src.clone())));
+            }
+        }
+        Ok(())
+    }
 
-        let topics = log.topics();
-        let mut resolved_params: Vec<String> = Vec::new();
+    /// Runs the transaction against the pending state with `eth_call` and `eth_estimateGas`,
+    /// a revert fails with its decoded reason. Returns the gas estimate
+    pub async fn simulate<P: Provider>(
+        &self,
+        provider: &P,
+        tx: &TransactionRequest,
+    ) -> Result<u64, RelayerError> {
+        let failed = |e: RpcError<TransportErrorKind>| {
+            let reason = match e
+                .as_error_resp()
+                .and_then(|payload| payload.as_revert_data())
+            {
+                Some(data) => self.decode_revert(&data),
+                None => e.to_string(),
+            };
+            RelayerError::SimulationFailed(reason)
+        };
+        provider
+            .call(tx.clone())
+            .block(BlockId::pending())
+            .await
+            .map_err(failed)?;
+        provider
+            .estimate_gas(tx.clone())
+            .block(BlockId::pending())
+            .await
+            .map_err(failed)
+    }
 
-        for (paramnum, param_str) in &self.params {
-            if param_str.starts_with("topic") {
-                let index_str = &param_str[5..];
-                let index: usize = index_str.parse().unwrap();
-                let topic_value = topics.get(index);
-                let topic: &FixedBytes<32>;
-                match topic_value {
-                    Some(t) => topic = t,
-                    None => return Err(Box::new(RelayerError::InvalidTopicMapping)),
+    /// Reason of a revert, a custom error of the action's ABI, `Error(string)` or `Panic(uint256)`,
+    /// anything else is shown as hex
+    fn decode_revert(&self, data: &[u8]) -> String {
+        if data.is_empty() {
+            return "reverted without a reason".to_string();
+        }
+        if let (Ok(abi), Some(selector)) =
+            (serde_json::from_str::<JsonAbi>(&self.abi), data.get(..4))
+        {
+            for error in abi.errors() {
+                if error.selector().as_slice() != selector {
+                    continue;
+                }
+                if let Ok(values) = error.abi_decode_input(&data[4..]) {
+                    let args: Vec<String> = values.iter().map(sol_value_to_string).collect();
+                    return format!("{}({})", error.name, args.join(", "));
                 }
+            }
+        }
+        match decode_revert_reason(data) {
+            Some(reason) => reason,
+            None => format!("0x{}", alloy::primitives::hex::encode(data)),
+        }
+    }
 
-                resolved_params.push(topic.to_string());
-            } else {
-                resolved_params.push(param_str.clone());
+    /// Runs the action's view calls against the chain, their decoded results feed `call:<n>` parameters
+    pub async fn read_views<P: Provider>(
+        &self,
+        provider: &P,
+        log: &Log,
+        trigger: &DecodedLog,
+    ) -> Result<Vec<DynSolValue>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut views = Vec::new();
+        for call in &self.calls {
+            let function = call.parse_function()?;
+            let target =
+                match self.resolve_param(&call.target, &DynSolType::Address, log, trigger, &[])? {
+                    DynSolValue::Address(target) => target,
+                    _ => return Err(Box::new(RelayerError::InvalidAddress)),
+                };
+            if call.args.len() != function.inputs.len() {
+                return Err(Box::new(RelayerError::InvalidArgsCount));
             }
+            let mut args = Vec::new();
+            for (arg, input) in call.args.iter().zip(&function.inputs) {
+                args.push(self.resolve_param(arg, &input.resolve()?, log, trigger, &[])?);
+            }
+            let request = TransactionRequest::default()
+                .to(target)
+                .with_input(Bytes::from(function.abi_encode_input(&args)?));
+            let output = provider.call(request).await?;
+            let mut values = function.abi_decode_output(&output)?;
+            views.push(values.swap_remove(call.output));
         }
-        let sol_values = self
-            .convert_strings_to_sol_values(&resolved_params, &function.inputs)
-            .unwrap();
-        let data = function.abi_encode_input(&sol_values).unwrap();
+        Ok(views)
+    }
+
+    /// Encodes the call for a trigger, `topicN`, `log.*`, `tx.hash`, `block.*` and decoded event parameters
+    /// are read from the trigger, `=` parameters are expressions over the same values
+    /// and `call:<n>` parameters take the result of the n-th view call
+    pub fn build_transaction(
+        &self,
+        log: &Log,
+        trigger: &DecodedLog,
+        views: &[DynSolValue],
+    ) -> Result<TransactionRequest, Box<dyn std::error::Error + Send + Sync>> {
+        let contract_addr = match Address::from_str(&self.contract_address) {
+            Ok(addr) => addr,
+            Err(_) => return Err(Box::new(RelayerError::InvalidAddress)),
+        };
+        let function = self.function()?;
+        if self.params.len() != function.inputs.len() {
+            return Err(Box::new(RelayerError::InvalidArgsCount));
+        }
+        let mut sol_values = Vec::new();
+        for ((_, param), input) in self.params.iter().zip(&function.inputs) {
+            sol_values.push(self.resolve_param(param, &input.resolve()?, log, trigger, views)?);
+        }
+        let data = function.abi_encode_input(&sol_values)?;
 
         let transaction = TransactionRequest::default()
             .to(contract_addr)
@@ -122,31 +651,95 @@ impl RawTransaction {
         Ok(transaction)
     }
 
-    fn convert_strings_to_sol_values<'a>(
+    /// Value of one parameter converted to the input type `ty`
+    fn resolve_param(
         &self,
-        params: &[String],
-        abi_inputs: &'a [Param],
-    ) -> Result<Vec<DynSolValue>, Box<dyn std::error::Error>> {
-        if params.len() != abi_inputs.len() {
-            return Err(Box::new(RelayerError::InvalidArgsCount));
-        }
-        let param_sols = params.iter().zip(abi_inputs.iter());
-        let mut solval: Vec<DynSolValue> = Vec::new();
-        for (param, abi_in) in param_sols {
-            let sol_type: DynSolType = match abi_in.ty.parse() {
-                Ok(solv) => solv,
-                Err(_) => return Err(Box::new(RelayerError::InvalidDataType)),
+        param_str: &str,
+        ty: &DynSolType,
+        log: &Log,
+        trigger: &DecodedLog,
+        views: &[DynSolValue],
+    ) -> Result<DynSolValue, Box<dyn std::error::Error + Send + Sync>> {
+        let missing = || Box::new(RelayerError::MissingParamValue(param_str.to_string()));
+        let value = if let Some(src) = param_str.strip_prefix('=') {
+            let value = Expr::parse(src)?.eval(&expr::param_values(trigger))?;
+            return Ok(value.to_sol(ty)?);
+        } else if let Some(index) = param_str.strip_prefix("call:") {
+            match index.parse::<usize>().ok().and_then(|i| views.get(i)) {
+                Some(value) => value.clone(),
+                None => return Err(missing()),
+            }
+        } else if let Some(field) = EventField::parse(param_str) {
+            match field.lookup(&trigger.args) {
+                Some(arg) => arg.value.clone(),
+                None => return Err(missing()),
+            }
+        } else if param_str.starts_with("log.") || param_str.starts_with("tx.") {
+            match log_param(param_str, trigger) {
+                Some(value) => value,
+                None => return Err(missing()),
+            }
+        } else if let Some(index_str) = param_str.strip_prefix("topic") {
+            let index: usize = match index_str.parse() {
+                Ok(index) => index,
+                Err(_) => return Err(Box::new(RelayerError::InvalidTopicMapping)),
             };
-
-            let solvalue = sol_type.coerce_str(&param);
-            match solvalue {
-                Ok(sol) => {
-                    solval.push(sol);
-                }
-                Err(_) => return Err(Box::new(RelayerError::InvalidDataType)),
+            match log.topics().get(index) {
+                Some(topic) => DynSolValue::FixedBytes(*topic, 32),
+                None => return Err(Box::new(RelayerError::InvalidTopicMapping)),
             }
-        }
+        } else if let Some(field) = param_str.strip_prefix("block.") {
+            let block = match &trigger.block {
+                Some(block) => block,
+                None => return Err(missing()),
+            };
+            let value = match field {
+                "number" => block.number.to_string(),
+                "hash" => block.hash.to_string(),
+                "timestamp" => block.timestamp.to_string(),
+                "baseFee" => match block.base_fee {
+                    Some(fee) => fee.to_string(),
+                    None => return Err(missing()),
+                },
+                _ => return Err(missing()),
+            };
+            DynSolValue::String(value)
+        } else {
+            DynSolValue::String(param_str.to_string())
+        };
+        // literals and block values are text, coerced like before
+        let converted = match value {
+            DynSolValue::String(text) if *ty != DynSolType::String => ty
+                .coerce_str(&text)
+                .map_err(|_| RelayerError::InvalidDataType),
+            value => convert_value(value, ty),
+        };
+        Ok(converted?)
+    }
+}
+
+/// How the steps of an action plan are sent for one trigger
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ActionOrder {
+    /// every step is sent right away
+    #[default]
+    Parallel,
+    /// a step is sent once the previous one settled, with `stop_on_failure` the rest is skipped after a failure
+    Sequential { stop_on_failure: bool },
+}
+
+/// The actions a subscription or schedule runs when it fires
+#[derive(Clone, Debug, Default)]
+pub struct ActionPlan {
+    pub steps: Vec<RawTransaction>,
+    pub order: ActionOrder,
+}
 
-        Ok(solval)
+impl ActionPlan {
+    pub fn single(step: RawTransaction) -> Self {
+        ActionPlan {
+            steps: vec![step],
+            order: ActionOrder::Parallel,
+        }
     }
 }